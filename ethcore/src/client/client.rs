@@ -1286,6 +1286,57 @@ impl Client {
 			_   => self.block_header(id).and_then(|h| h.decode().ok())
 		}
 	}
+
+	/// Check that an ancient block is not in the chain yet and that its parent is known.
+	/// The parent may also be one of the `pending` blocks about to be queued along with it.
+	fn check_ancient_block(&self, unverified: &Unverified, pending: &HashSet<H256>) -> EthcoreResult<()> {
+		if self.chain.read().is_known(&unverified.hash()) {
+			bail!(EthcoreErrorKind::Import(ImportErrorKind::AlreadyInChain));
+		}
+		let parent_hash = unverified.parent_hash();
+		// NOTE To prevent race condition with import, make sure to check queued blocks first
+		// (and attempt to acquire lock)
+		let is_parent_pending = pending.contains(&parent_hash) || self.queued_ancient_blocks.read().0.contains(&parent_hash);
+		if !is_parent_pending {
+			let status = self.block_status(BlockId::Hash(parent_hash));
+			if  status == BlockStatus::Unknown {
+				bail!(EthcoreErrorKind::Block(BlockError::UnknownParent(parent_hash)));
+			}
+		}
+		Ok(())
+	}
+
+	/// Trigger an IO message importing queued ancient blocks, `count` of which were just queued.
+	fn queue_ancient_import(&self, count: usize) -> Result<(), QueueError> {
+		let queued = self.queued_ancient_blocks.clone();
+		let lock = self.ancient_blocks_import_lock.clone();
+		let max_blocks = cmp::max(count, MAX_ANCIENT_BLOCKS_TO_IMPORT);
+		self.queue_ancient_blocks.queue(&self.io_channel.read(), count, move |client| {
+			trace_time!("import_ancient_block");
+			// Make sure to hold the lock here to prevent importing out of order.
+			// We use separate lock, cause we don't want to block queueing.
+			let _lock = lock.lock();
+			for _i in 0..max_blocks {
+				let first = queued.write().1.pop_front();
+				if let Some((unverified, receipts_bytes)) = first {
+					let hash = unverified.hash();
+					let result = client.importer.import_old_block(
+						unverified,
+						&receipts_bytes,
+						&**client.db.read().key_value(),
+						&*client.chain.read(),
+					);
+					if let Err(e) = result {
+						error!(target: "client", "Error importing ancient block: {}", e);
+					}
+					// remove from pending
+					queued.write().0.remove(&hash);
+				} else {
+					break;
+				}
+			}
+		})
+	}
 }
 
 impl snapshot::DatabaseRestore for Client {
@@ -2121,22 +2172,7 @@ impl IoClient for Client {
 		trace_time!("queue_ancient_block");
 
 		let hash = unverified.hash();
-		{
-			// check block order
-			if self.chain.read().is_known(&hash) {
-				bail!(EthcoreErrorKind::Import(ImportErrorKind::AlreadyInChain));
-			}
-			let parent_hash = unverified.parent_hash();
-			// NOTE To prevent race condition with import, make sure to check queued blocks first
-			// (and attempt to acquire lock)
-			let is_parent_pending = self.queued_ancient_blocks.read().0.contains(&parent_hash);
-			if !is_parent_pending {
-				let status = self.block_status(BlockId::Hash(parent_hash));
-				if  status == BlockStatus::Unknown {
-					bail!(EthcoreErrorKind::Block(BlockError::UnknownParent(parent_hash)));
-				}
-			}
-		}
+		self.check_ancient_block(&unverified, &HashSet::new())?;
 
 		// we queue blocks here and trigger an IO message.
 		{
@@ -2145,35 +2181,67 @@ impl IoClient for Client {
 			queued.1.push_back((unverified, receipts_bytes));
 		}
 
-		let queued = self.queued_ancient_blocks.clone();
-		let lock = self.ancient_blocks_import_lock.clone();
-		self.queue_ancient_blocks.queue(&self.io_channel.read(), 1, move |client| {
-			trace_time!("import_ancient_block");
-			// Make sure to hold the lock here to prevent importing out of order.
-			// We use separate lock, cause we don't want to block queueing.
-			let _lock = lock.lock();
-			for _i in 0..MAX_ANCIENT_BLOCKS_TO_IMPORT {
-				let first = queued.write().1.pop_front();
-				if let Some((unverified, receipts_bytes)) = first {
-					let hash = unverified.hash();
-					let result = client.importer.import_old_block(
-						unverified,
-						&receipts_bytes,
-						&**client.db.read().key_value(),
-						&*client.chain.read(),
-					);
-					if let Err(e) = result {
-						error!(target: "client", "Error importing ancient block: {}", e);
+		self.queue_ancient_import(1)?;
+
+		Ok(hash)
+	}
+
+	fn queue_ancient_block_batch(&self, blocks: Vec<(Unverified, Bytes)>) -> Vec<EthcoreResult<H256>> {
+		trace_time!("queue_ancient_block_batch");
+
+		let mut results = Vec::with_capacity(blocks.len());
+		let mut accepted = Vec::with_capacity(blocks.len());
+		let mut pending = HashSet::new();
+		for (unverified, receipts_bytes) in blocks {
+			let hash = unverified.hash();
+			match self.check_ancient_block(&unverified, &pending) {
+				Ok(()) => {
+					pending.insert(hash);
+					accepted.push((unverified, receipts_bytes));
+					results.push(Ok(hash));
+				},
+				Err(e) => {
+					let known = match *e.kind() {
+						EthcoreErrorKind::Import(ImportErrorKind::AlreadyInChain) => true,
+						_ => false,
+					};
+					results.push(Err(e));
+					if !known {
+						break;
 					}
-					// remove from pending
-					queued.write().0.remove(&hash);
-				} else {
-					break;
+				},
+			}
+		}
+
+		if accepted.is_empty() {
+			return results;
+		}
+
+		// All the accepted blocks are queued at once and imported by a single IO message.
+		let count = accepted.len();
+		{
+			let mut queued = self.queued_ancient_blocks.write();
+			for (unverified, receipts_bytes) in accepted {
+				queued.0.insert(unverified.hash());
+				queued.1.push_back((unverified, receipts_bytes));
+			}
+		}
+
+		if let Err(e) = self.queue_ancient_import(count) {
+			// Nothing is going to import the blocks, take them back and fail from the first one.
+			{
+				let mut queued = self.queued_ancient_blocks.write();
+				queued.1.retain(|&(ref unverified, _)| !pending.contains(&unverified.hash()));
+				for hash in &pending {
+					queued.0.remove(hash);
 				}
 			}
-		})?;
+			let first = results.iter().position(|result| result.is_ok()).expect("at least one block was accepted; qed");
+			results.truncate(first);
+			results.push(Err(e.into()));
+		}
 
-		Ok(hash)
+		results
 	}
 
 	fn queue_consensus_message(&self, message: Bytes) {
@@ -47,7 +47,7 @@ use header::{Header as BlockHeader, BlockNumber};
 use filter::Filter;
 use log_entry::LocalizedLogEntry;
 use receipt::{Receipt, LocalizedReceipt, TransactionOutcome};
-use error::{Error, EthcoreResult};
+use error::{Error, ErrorKind, EthcoreResult, ImportErrorKind, QueueErrorKind};
 use vm::Schedule;
 use miner::{self, Miner, MinerService};
 use spec::Spec;
@@ -115,6 +115,12 @@ pub struct TestBlockChainClient {
 	pub history: RwLock<Option<u64>>,
 	/// Is disabled
 	pub disabled: AtomicBool,
+	/// Number of calls queueing ancient blocks.
+	pub ancient_queue_calls: AtomicUsize,
+	/// Block number above which imports fail because the queue is full.
+	pub queue_full_above: RwLock<Option<BlockNumber>>,
+	/// Report blocks already in chain as such when importing them again.
+	pub reject_known_blocks: AtomicBool,
 }
 
 /// Used for generating test client blocks.
@@ -181,6 +187,9 @@ impl TestBlockChainClient {
 			traces: RwLock::new(None),
 			history: RwLock::new(None),
 			disabled: AtomicBool::new(false),
+			ancient_queue_calls: AtomicUsize::new(0),
+			queue_full_above: RwLock::new(None),
+			reject_known_blocks: AtomicBool::new(false),
 			error_on_logs: RwLock::new(None),
 		};
 
@@ -526,6 +535,14 @@ impl ImportBlock for TestBlockChainClient {
 	fn import_block(&self, unverified: Unverified) -> EthcoreResult<H256> {
 		let header = unverified.header;
 		let h = header.hash();
+		if self.reject_known_blocks.load(AtomicOrder::Relaxed) && self.blocks.read().contains_key(&h) {
+			return Err(ErrorKind::Import(ImportErrorKind::AlreadyInChain).into());
+		}
+		if let Some(limit) = *self.queue_full_above.read() {
+			if header.number() > limit {
+				return Err(ErrorKind::Queue(QueueErrorKind::Full(limit as usize)).into());
+			}
+		}
 		let number: usize = header.number() as usize;
 		if number > self.blocks.read().len() {
 			panic!("Unexpected block number. Expected {}, got {}", self.blocks.read().len(), number);
@@ -888,9 +905,27 @@ impl IoClient for TestBlockChainClient {
 	}
 
 	fn queue_ancient_block(&self, unverified: Unverified, _r: Bytes) -> EthcoreResult<H256> {
+		self.ancient_queue_calls.fetch_add(1, AtomicOrder::Relaxed);
 		self.import_block(unverified)
 	}
 
+	fn queue_ancient_block_batch(&self, blocks: Vec<(Unverified, Bytes)>) -> Vec<EthcoreResult<H256>> {
+		self.ancient_queue_calls.fetch_add(1, AtomicOrder::Relaxed);
+		let mut results = Vec::with_capacity(blocks.len());
+		for (unverified, _r) in blocks {
+			let result = self.import_block(unverified);
+			let stop = match result {
+				Ok(_) | Err(Error(ErrorKind::Import(ImportErrorKind::AlreadyInChain), _)) => false,
+				Err(_) => true,
+			};
+			results.push(result);
+			if stop {
+				break;
+			}
+		}
+		results
+	}
+
 	fn queue_consensus_message(&self, message: Bytes) {
 		self.spec.engine.handle_message(&message).unwrap();
 	}
@@ -24,7 +24,7 @@ use blockchain::TreeRoute;
 use client::Mode;
 use encoded;
 use vm::LastHashes;
-use error::{Error, CallError, EthcoreResult};
+use error::{Error, ErrorKind, ImportErrorKind, CallError, EthcoreResult};
 use evm::Schedule;
 use executive::Executed;
 use filter::Filter;
@@ -207,6 +207,26 @@ pub trait IoClient: Sync + Send {
 	/// Queue block import with transaction receipts. Does no sealing and transaction validation.
 	fn queue_ancient_block(&self, block_bytes: Unverified, receipts_bytes: Bytes) -> EthcoreResult<H256>;
 
+	/// Queue a batch of blocks with transaction receipts, in order. Returns a result for each
+	/// block up to and including the first one that failed for a reason other than being already known.
+	fn queue_ancient_block_batch(&self, blocks: Vec<(Unverified, Bytes)>) -> Vec<EthcoreResult<H256>> {
+		let mut results = Vec::with_capacity(blocks.len());
+		for (block_bytes, receipts_bytes) in blocks {
+			let result = self.queue_ancient_block(block_bytes, receipts_bytes);
+			let stop = match result {
+				Ok(_) => false,
+				Err(Error(ErrorKind::Import(ImportErrorKind::AlreadyInChain), _)) => false,
+				Err(Error(ErrorKind::Import(ImportErrorKind::AlreadyQueued), _)) => false,
+				Err(_) => true,
+			};
+			results.push(result);
+			if stop {
+				break;
+			}
+		}
+		results
+	}
+
 	/// Queue conensus engine message.
 	fn queue_consensus_message(&self, message: Bytes);
 }
@@ -18,23 +18,40 @@
 /// Blockchain downloader
 ///
 
-use std::collections::{HashSet, VecDeque};
+use std::collections::{HashSet, HashMap, VecDeque};
 use std::cmp;
+use std::time::{Duration, Instant};
 use heapsize::HeapSizeOf;
 use ethereum_types::H256;
-use rlp::{self, Rlp};
+use hash::KECCAK_NULL_RLP;
+use rlp::{self, Rlp, RlpStream};
 use ethcore::header::BlockNumber;
-use ethcore::client::{BlockStatus, BlockId};
+use ethcore::client::{BlockChainClient, BlockStatus, BlockId};
 use ethcore::error::{ImportErrorKind, QueueErrorKind, BlockError, Error as EthcoreError, ErrorKind as EthcoreErrorKind};
+use ethcore::verification::queue::kind::blocks::Unverified;
+use network::PeerId;
 use sync_io::SyncIo;
-use blocks::{BlockCollection, SyncBody, SyncHeader};
+use blocks::{BlockAndReceipts, BlockCollection, BodiesOrder, SyncBody, SyncHeader};
+use chain::BlockSet;
 
 const MAX_HEADERS_TO_REQUEST: usize = 128;
 const MAX_BODIES_TO_REQUEST: usize = 32;
 const MAX_RECEPITS_TO_REQUEST: usize = 128;
 const SUBCHAIN_SIZE: u64 = 256;
+const MIN_SUBCHAIN_SIZE: u64 = 8;
 const MAX_ROUND_PARENTS: usize = 16;
 const MAX_PARALLEL_SUBCHAIN_DOWNLOAD: usize = 5;
+const MAX_SUBCHAIN_HEADS: usize = 256;
+const MAX_RETRACT_STEP: u64 = 1 << 16;
+const MAX_DOWNLOAD_MEMORY: usize = 256 * 1024 * 1024;
+const MAX_IMPORT_RATE_SAMPLES: usize = 16;
+const MIN_IMPORT_RATE_SAMPLES: usize = 2;
+const MIN_USELESS_HEADERS_PER_ROUND: usize = 2;
+const MAX_USELESS_HEADERS_PER_ROUND: usize = 3;
+const MAX_INVALID_BODIES_PER_ROUND: usize = 3;
+const MIN_HEADERS_TO_REQUEST: usize = 16;
+/// Upper bounds, in milliseconds, of all but the last block import latency bucket.
+const IMPORT_LATENCY_BUCKETS_MS: [u64; 3] = [1, 10, 100];
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 /// Downloader state
@@ -47,14 +64,90 @@ pub enum State {
 	Blocks,
 	/// Download is complete
 	Complete,
+	/// Download is halted until resumed, downloaded data is kept.
+	Paused,
+}
+
+/// Block downloader configuration.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct BlockDownloaderConfig {
+	/// Maximum number of headers requested from a peer at once.
+	pub max_headers_to_request: usize,
+	/// Maximum number of block bodies requested from a peer at once.
+	pub max_bodies_to_request: usize,
+	/// Maximum number of block receipts requested from a peer at once.
+	pub max_receipts_to_request: usize,
+	/// Number of subchain heads requested when looking for a better chain.
+	pub subchain_size: u64,
+	/// Least number of subchain heads requested when the highest block is close.
+	pub min_subchain_size: u64,
+	/// Maximum number of peers downloading subchain heads in parallel.
+	pub max_parallel_subchain_download: usize,
+	/// Maximum number of subchain heads downloaded in a single round.
+	pub max_heads: usize,
+	/// Maximum number of blocks to step back in a single round when seeking a common block.
+	pub max_retract_step: u64,
+	/// Soft limit on the memory used by downloaded blocks. No new headers are requested above it.
+	pub memory_limit: usize,
+	/// Count blocks found already in the chain or the import queue as progress of a round. Off by default,
+	/// so that a round importing nothing new makes the next round step back to look for a common block.
+	pub count_known_blocks: bool,
+}
+
+impl Default for BlockDownloaderConfig {
+	fn default() -> Self {
+		BlockDownloaderConfig {
+			max_headers_to_request: MAX_HEADERS_TO_REQUEST,
+			max_bodies_to_request: MAX_BODIES_TO_REQUEST,
+			max_receipts_to_request: MAX_RECEPITS_TO_REQUEST,
+			subchain_size: SUBCHAIN_SIZE,
+			min_subchain_size: MIN_SUBCHAIN_SIZE,
+			max_parallel_subchain_download: MAX_PARALLEL_SUBCHAIN_DOWNLOAD,
+			max_heads: MAX_SUBCHAIN_HEADS,
+			max_retract_step: MAX_RETRACT_STEP,
+			memory_limit: MAX_DOWNLOAD_MEMORY,
+			count_known_blocks: false,
+		}
+	}
+}
+
+/// Snapshot of the downloader progress.
+#[derive(Clone, Debug)]
+pub struct BlockDownloaderStatus {
+	/// Downloader state.
+	pub state: State,
+	/// Best imported block number.
+	pub last_imported_block: BlockNumber,
+	/// Highest block number seen.
+	pub highest_block: Option<BlockNumber>,
+	/// Number of blocks imported this round.
+	pub imported_this_round: Option<usize>,
+	/// Number of downloaded blocks waiting to be imported.
+	pub queued_blocks: usize,
+}
+
+/// Minimal downloader state required to resume an interrupted sync.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct DownloaderCheckpoint {
+	/// Last imported block hash.
+	pub last_imported_hash: H256,
+	/// Last imported block number.
+	pub last_imported_number: BlockNumber,
+	/// Highest block number seen.
+	pub highest_block: Option<BlockNumber>,
+	/// Probing range for seeking common best block.
+	pub retract_step: u64,
 }
 
 /// Data that needs to be requested from a peer.
+#[derive(Eq, PartialEq, Debug)]
 pub enum BlockRequest {
 	Headers {
 		start: H256,
 		count: u64,
 		skip: u64,
+		/// Head of the subchain the headers are downloaded for, `None` for subchain heads.
+		subchain: Option<H256>,
 	},
 	Bodies {
 		hashes: Vec<H256>,
@@ -62,9 +155,23 @@ pub enum BlockRequest {
 	Receipts {
 		hashes: Vec<H256>,
 	},
+	/// Consecutive headers by block number, to fill a gap no subchain head leads to.
+	HeadersByNumber {
+		start_number: BlockNumber,
+		count: u64,
+	},
+}
+
+/// Identifies a piece of block data requested from a peer.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+enum RequestKey {
+	Header(H256),
+	Body(H256),
+	Receipt(H256),
 }
 
 /// Indicates sync action
+#[derive(Eq, PartialEq, Debug)]
 pub enum DownloadAction {
 	/// Do nothing
 	None,
@@ -72,17 +179,127 @@ pub enum DownloadAction {
 	Reset
 }
 
+/// Reason for rejecting a peer message as invalid.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum InvalidReason {
+	/// Message could not be decoded.
+	BadRlp,
+	/// Response contains a block known to be bad.
+	KnownBadBlock,
+	/// Response does not contain the requested header.
+	UnexpectedHash,
+	/// Peer chain has no block in common with ours.
+	NoCommonBlock,
+	/// Subchain heads are closer to each other than requested.
+	HeadsTooClose,
+	/// Consecutive headers are not linked by their parent hash.
+	BrokenChain,
+	/// Header at a pinned height, e.g. set with `require_block`, has another hash.
+	WrongFork,
+	/// Block bodies do not match the requested headers.
+	BodiesMismatch,
+	/// More block receipts than requested.
+	TooManyReceipts,
+	/// Block receipts do not match the requested headers.
+	ReceiptsMismatch,
+	/// Downloaded block failed import verification.
+	BadBlock,
+	/// Announced block is too old to be a new block.
+	StaleBlock,
+	/// Peer status is not compatible with our chain or protocol.
+	IncompatibleStatus,
+	/// Snapshot manifest version is not supported.
+	UnsupportedSnapshot,
+}
+
+/// Why the downloader dropped the data of its current round.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ResetReason {
+	/// Requested by the owner of the downloader.
+	Requested,
+	/// New subchains to download were set.
+	NewSubchains,
+	/// All downloaded blocks have been imported.
+	RoundComplete,
+	/// Too many useless headers responses this round.
+	UselessHeaders,
+	/// Too many invalid bodies responses this round.
+	InvalidBodies,
+	/// Could not step back to look for a block in common with peers.
+	RetractFailed,
+}
+
 #[derive(Eq, PartialEq, Debug)]
 pub enum BlockDownloaderImportError {
 	/// Imported data is rejected as invalid. Peer should be dropped.
-	Invalid,
+	Invalid(InvalidReason),
 	/// Imported data is valid but rejected cause the downloader does not need it.
 	Useless,
 }
 
 impl From<rlp::DecoderError> for BlockDownloaderImportError {
 	fn from(_: rlp::DecoderError) -> BlockDownloaderImportError {
-		BlockDownloaderImportError::Invalid
+		BlockDownloaderImportError::Invalid(InvalidReason::BadRlp)
+	}
+}
+
+/// How much a peer response advanced the download. Invalid responses are reported as errors instead.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ResponseQuality {
+	/// Response contained new data.
+	Useful,
+	/// Response was valid but not needed, e.g. it arrived after the downloader moved on.
+	Redundant,
+	/// Response did not advance the download. Useless block headers, and empty block bodies
+	/// received away from the chain head, are reported as `BlockDownloaderImportError::Useless` instead.
+	Useless,
+}
+
+/// Outcome of importing downloaded blocks.
+#[derive(Eq, PartialEq, Debug)]
+pub struct CollectOutcome {
+	/// Import result, as returned by `collect_blocks`.
+	pub result: Result<(), BlockDownloaderImportError>,
+	/// Downloaded blocks that were dropped without being imported.
+	pub abandoned: Vec<H256>,
+	/// Number of blocks newly queued for import.
+	pub imported: usize,
+	/// Time spent importing, to let the caller hold back requests while the import lags.
+	pub elapsed: Duration,
+}
+
+/// What the headers of a single response turned out to be.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug)]
+pub struct HeadersBreakdown {
+	/// Headers of blocks unknown to the chain.
+	pub new: usize,
+	/// Headers already downloaded this round.
+	pub already_contained: usize,
+	/// Headers above the max block.
+	pub above_max_block: usize,
+	/// Headers of blocks already in the chain.
+	pub in_chain: usize,
+	/// Headers of blocks already in the import queue.
+	pub queued: usize,
+	/// Headers of known bad blocks.
+	pub bad: usize,
+}
+
+/// Number of bytes received per request type since the downloader was created.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug)]
+pub struct TransferStats {
+	/// Raw size of received block headers.
+	pub headers_bytes: u64,
+	/// Raw size of received block bodies.
+	pub bodies_bytes: u64,
+	/// Raw size of received block receipts.
+	pub receipts_bytes: u64,
+}
+
+impl TransferStats {
+	/// Raw size of all received block data.
+	pub fn total_bytes(&self) -> u64 {
+		self.headers_bytes + self.bodies_bytes + self.receipts_bytes
 	}
 }
 
@@ -91,6 +308,8 @@ impl From<rlp::DecoderError> for BlockDownloaderImportError {
 pub struct BlockDownloader {
 	/// Downloader state
 	state: State,
+	/// State to go back to when resumed, set while paused.
+	paused_state: Option<State>,
 	/// Highest block number seen
 	highest_block: Option<BlockNumber>,
 	/// Downloaded blocks, holds `H`, `B` and `S`
@@ -101,6 +320,10 @@ pub struct BlockDownloader {
 	last_imported_hash: H256,
 	/// Number of blocks imported this round
 	imported_this_round: Option<usize>,
+	/// Number of consecutive rounds that imported nothing.
+	rounds_without_progress: usize,
+	/// Why the downloader was last reset.
+	last_reset_reason: Option<ResetReason>,
 	/// Block number the last round started with.
 	last_round_start: BlockNumber,
 	last_round_start_hash: H256,
@@ -108,20 +331,73 @@ pub struct BlockDownloader {
 	round_parents: VecDeque<(H256, H256)>,
 	/// Do we need to download block recetips.
 	download_receipts: bool,
-	/// Sync up to the block with this hash.
-	target_hash: Option<H256>,
+	/// Are blocks queued as ancient blocks along with their receipts. Set for old blocks downloads,
+	/// whether receipts are downloaded or not. Blocks with transactions but no downloaded receipts
+	/// are imported as new blocks instead.
+	import_ancient: bool,
+	/// Sync through these block hashes, up to the last one.
+	targets: Vec<H256>,
+	/// Intermediate targets imported so far.
+	reached_targets: Vec<H256>,
+	/// Do not download blocks above this number.
+	max_block: Option<BlockNumber>,
+	/// Number of consecutive useless headers responses this round.
+	useless_headers_count: usize,
+	/// Number of consecutive invalid bodies responses this round.
+	invalid_bodies_count: usize,
 	/// Probing range for seeking common best block.
 	retract_step: u64,
+	/// Number of subchain heads requested this round.
+	round_subchain_size: u64,
 	/// Whether reorg should be limited.
 	limit_reorg: bool,
+	/// Last imported block number before the downloader started stepping back to find a common block.
+	retract_from: Option<BlockNumber>,
+	/// Number of blocks stepped back before a common block was last found.
+	last_reorg_depth: Option<u64>,
+	/// Order in which block bodies are requested.
+	bodies_order: BodiesOrder,
+	/// Request size limits.
+	config: BlockDownloaderConfig,
+	/// Time each outstanding header, body or receipt request was issued.
+	request_issued_at: HashMap<RequestKey, Instant>,
+	/// Recent (time, last imported block number) samples used to estimate the import rate.
+	import_samples: VecDeque<(Instant, BlockNumber)>,
+	/// Bytes received so far.
+	transfer_stats: TransferStats,
+	/// Number of downloaded blocks imported so far.
+	blocks_imported: u64,
+	/// What the headers of the last response turned out to be.
+	last_response_breakdown: HeadersBreakdown,
+	/// Header range requested by number to fill a gap, if any.
+	header_range_request: Option<(BlockNumber, u64)>,
+	/// Blocks that must never be downloaded or imported.
+	blacklisted: HashSet<H256>,
+	/// Hashes the blocks at these heights must have.
+	required_blocks: HashMap<BlockNumber, H256>,
+	/// Number of block import calls by latency, see `IMPORT_LATENCY_BUCKETS_MS`.
+	import_latency: [u64; 4],
 }
 
 impl BlockDownloader {
 	/// Create a new instance of syncing strategy. This won't reorganize to before the
 	/// last kept state.
 	pub fn new(sync_receipts: bool, start_hash: &H256, start_number: BlockNumber) -> Self {
+		BlockDownloader::new_with_config(sync_receipts, start_hash, start_number, BlockDownloaderConfig::default())
+	}
+
+	/// Create a new instance of syncing strategy with custom request limits.
+	pub fn new_with_config(sync_receipts: bool, start_hash: &H256, start_number: BlockNumber, config: BlockDownloaderConfig) -> Self {
+		// Subchain heads are requested `max_headers_to_request - 2` blocks apart,
+		// and a zero retract step would never move back to find a common block.
+		let config = BlockDownloaderConfig {
+			max_headers_to_request: cmp::max(config.max_headers_to_request, 2),
+			max_retract_step: cmp::max(config.max_retract_step, 1),
+			..config
+		};
 		BlockDownloader {
 			state: State::Idle,
+			paused_state: None,
 			highest_block: None,
 			last_imported_block: start_number,
 			last_imported_hash: start_hash.clone(),
@@ -129,37 +405,130 @@ impl BlockDownloader {
 			last_round_start_hash: start_hash.clone(),
 			blocks: BlockCollection::new(sync_receipts),
 			imported_this_round: None,
+			rounds_without_progress: 0,
+			last_reset_reason: None,
 			round_parents: VecDeque::new(),
 			download_receipts: sync_receipts,
-			target_hash: None,
+			import_ancient: sync_receipts,
+			targets: Vec::new(),
+			reached_targets: Vec::new(),
+			max_block: None,
+			useless_headers_count: 0,
+			invalid_bodies_count: 0,
 			retract_step: 1,
+			round_subchain_size: config.subchain_size,
 			limit_reorg: true,
+			retract_from: None,
+			last_reorg_depth: None,
+			bodies_order: BodiesOrder::Chain,
+			config,
+			request_issued_at: HashMap::new(),
+			import_samples: VecDeque::new(),
+			transfer_stats: TransferStats::default(),
+			blocks_imported: 0,
+			last_response_breakdown: HeadersBreakdown::default(),
+			header_range_request: None,
+			blacklisted: HashSet::new(),
+			required_blocks: HashMap::new(),
+			import_latency: [0; 4],
 		}
 	}
 
 	/// Create a new instance of sync with unlimited reorg allowed.
 	pub fn with_unlimited_reorg(sync_receipts: bool, start_hash: &H256, start_number: BlockNumber) -> Self {
 		BlockDownloader {
-			state: State::Idle,
-			highest_block: None,
-			last_imported_block: start_number,
-			last_imported_hash: start_hash.clone(),
-			last_round_start: start_number,
-			last_round_start_hash: start_hash.clone(),
-			blocks: BlockCollection::new(sync_receipts),
-			imported_this_round: None,
-			round_parents: VecDeque::new(),
-			download_receipts: sync_receipts,
-			target_hash: None,
-			retract_step: 1,
 			limit_reorg: false,
+			..BlockDownloader::new(sync_receipts, start_hash, start_number)
+		}
+	}
+
+	/// Create a new instance of syncing strategy for the blocks at the chain head.
+	/// Bodies of the most recent blocks are requested first once the download reaches the chain head.
+	pub fn for_new_blocks(start_hash: &H256, start_number: BlockNumber) -> Self {
+		BlockDownloader {
+			bodies_order: BodiesOrder::NewestFirst,
+			..BlockDownloader::new(false, start_hash, start_number)
+		}
+	}
+
+	/// Create a new instance of sync resuming from a previously saved checkpoint.
+	pub fn from_checkpoint(block_set: BlockSet, checkpoint: &DownloaderCheckpoint) -> Self {
+		let hash = &checkpoint.last_imported_hash;
+		let number = checkpoint.last_imported_number;
+		let mut downloader = match block_set {
+			BlockSet::NewBlocks => BlockDownloader::for_new_blocks(hash, number),
+			BlockSet::OldBlocks => BlockDownloader::with_unlimited_reorg(true, hash, number),
+		};
+		downloader.highest_block = checkpoint.highest_block;
+		downloader.retract_step = cmp::max(checkpoint.retract_step, 1);
+		downloader
+	}
+
+	/// Returns the state required to resume this sync with `from_checkpoint`.
+	pub fn checkpoint(&self) -> DownloaderCheckpoint {
+		DownloaderCheckpoint {
+			last_imported_hash: self.last_imported_hash,
+			last_imported_number: self.last_imported_block,
+			highest_block: self.highest_block,
+			retract_step: self.retract_step,
 		}
 	}
 
-	/// Reset sync. Clear all local downloaded data.
-	pub fn reset(&mut self) {
+	/// Reset sync. Clear all local downloaded data. A paused download stays paused and resumes
+	/// from the idle state.
+	pub fn reset(&mut self, reason: ResetReason) {
+		debug!(target: "sync", "Resetting block downloader: {:?}", reason);
+		self.last_reset_reason = Some(reason);
+		// `round_parents` is kept on purpose: it is bounded by `MAX_ROUND_PARENTS` and lets the next
+		// round step back to the parent of the round start without probing the chain.
 		self.blocks.clear();
-		self.state = State::Idle;
+		self.request_issued_at.clear();
+		self.header_range_request = None;
+		self.useless_headers_count = 0;
+		self.invalid_bodies_count = 0;
+		self.set_state(State::Idle);
+	}
+
+	/// Move to the given state, or to resume in it if paused.
+	fn set_state(&mut self, state: State) {
+		if self.state == State::Paused {
+			self.paused_state = Some(state);
+		} else {
+			self.state = state;
+		}
+	}
+
+	/// Stop issuing requests until `resume` is called. Downloaded blocks and counters are kept.
+	pub fn pause(&mut self) {
+		if self.state != State::Paused {
+			trace!(target: "sync", "Pausing block download in state {:?}", self.state);
+			self.paused_state = Some(self.state);
+			self.state = State::Paused;
+		}
+	}
+
+	/// Continue a download stopped with `pause` from where it was.
+	pub fn resume(&mut self) {
+		if let Some(state) = self.paused_state.take() {
+			trace!(target: "sync", "Resuming block download in state {:?}", state);
+			self.state = state;
+		}
+	}
+
+	/// Start or stop downloading block receipts, e.g. when ancient receipts are no longer needed.
+	/// Receipts requested or downloaded so far are dropped when stopping. Blocks without transactions
+	/// are still queued as ancient blocks, others are imported as new blocks.
+	pub fn set_download_receipts(&mut self, enabled: bool) {
+		if enabled == self.download_receipts {
+			return;
+		}
+		debug!(target: "sync", "{} block receipts download", if enabled { "Starting" } else { "Stopping" });
+		self.download_receipts = enabled;
+		self.blocks.set_need_receipts(enabled);
+		self.request_issued_at.retain(|key, _| match *key {
+			RequestKey::Receipt(_) => false,
+			_ => true,
+		});
 	}
 
 	/// Mark a block as known in the chain
@@ -185,28 +554,234 @@ impl BlockDownloader {
 
 	/// Set starting sync block
 	pub fn set_target(&mut self, hash: &H256) {
-		self.target_hash = Some(hash.clone());
+		self.set_targets(vec![hash.clone()]);
+	}
+
+	/// Set blocks the sync must pass through. The sync is complete once the last one is reached.
+	pub fn set_targets(&mut self, hashes: Vec<H256>) {
+		self.targets.clear();
+		for h in hashes {
+			if !self.targets.contains(&h) {
+				self.targets.push(h);
+			}
+		}
+		self.reached_targets.clear();
+	}
+
+	/// Returns intermediate targets that have been imported.
+	pub fn reached_targets(&self) -> &[H256] {
+		&self.reached_targets
+	}
+
+	/// Set the highest block number to download. The sync is complete once it is imported.
+	pub fn set_max_block(&mut self, max_block: Option<BlockNumber>) {
+		self.max_block = max_block;
+		// Headers above the max block are skipped, so it is also the highest block to expect.
+		if max_block.is_some() {
+			self.highest_block = max_block;
+		}
+	}
+
+	/// Highest block number seen in headers or set as the max block, if any.
+	pub fn highest_block(&self) -> Option<BlockNumber> {
+		self.highest_block
 	}
 
 	/// Unmark header as being downloaded.
 	pub fn clear_header_download(&mut self, hash: &H256) {
+		self.request_issued_at.remove(&RequestKey::Header(*hash));
 		self.blocks.clear_header_download(hash)
 	}
 
+	/// Unmark the header range as being downloaded.
+	pub fn clear_header_range_download(&mut self) {
+		self.header_range_request = None;
+	}
+
 	/// Unmark block body as being downloaded.
 	pub fn clear_body_download(&mut self, hashes: &[H256]) {
+		for h in hashes {
+			self.request_issued_at.remove(&RequestKey::Body(*h));
+		}
 		self.blocks.clear_body_download(hashes)
 	}
 
 	/// Unmark block receipt as being downloaded.
 	pub fn clear_receipt_download(&mut self, hashes: &[H256]) {
+		for h in hashes {
+			self.request_issued_at.remove(&RequestKey::Receipt(*h));
+		}
 		self.blocks.clear_receipt_download(hashes)
 	}
+
+	/// Make the missing receipts of the given blocks requestable again, e.g. after the peer they were
+	/// requested from timed out. Bodies are not affected.
+	pub fn requeue_receipts(&mut self, hashes: &[H256]) {
+		for h in hashes {
+			self.request_issued_at.remove(&RequestKey::Receipt(*h));
+		}
+		let requeued = self.blocks.requeue_receipts(hashes);
+		trace!(target: "sync", "Requeued receipts of {} of {} blocks", requeued, hashes.len());
+	}
+
+	/// Stop downloading the subchain with the given head, dropping the blocks downloaded for it.
+	/// Other subchains are left intact. Returns `false` if there is no such subchain head.
+	pub fn cancel_subchain(&mut self, head: &H256) -> bool {
+		if !self.blocks.remove_head(head) {
+			return false;
+		}
+		let blocks = &self.blocks;
+		self.request_issued_at.retain(|key, _| match *key {
+			RequestKey::Header(h) => blocks.contains_head(&h),
+			RequestKey::Body(h) | RequestKey::Receipt(h) => blocks.contains(&h),
+		});
+		true
+	}
+
+	/// Unmark all headers, bodies and receipts that have been requested more than `timeout` ago,
+	/// so that they can be requested from another peer. Returns the freed block hashes.
+	pub fn expire_requests(&mut self, timeout: Duration) -> HashSet<H256> {
+		self.expire_requests_at(Instant::now(), timeout)
+	}
+
+	fn expire_requests_at(&mut self, now: Instant, timeout: Duration) -> HashSet<H256> {
+		let expired: Vec<RequestKey> = self.request_issued_at.iter()
+			.filter(|&(_, issued)| *issued + timeout <= now)
+			.map(|(key, _)| *key)
+			.collect();
+		let mut freed = HashSet::new();
+		for key in expired {
+			trace!(target: "sync", "Request expired: {:?}", key);
+			let hash = match key {
+				RequestKey::Header(h) => { self.clear_header_download(&h); h },
+				RequestKey::Body(h) => { self.clear_body_download(&[h]); h },
+				RequestKey::Receipt(h) => { self.clear_receipt_download(&[h]); h },
+			};
+			freed.insert(hash);
+		}
+		freed
+	}
+
+	/// Move the start of the first subchain past blocks that are already in our chain,
+	/// so that their headers are not downloaded again.
+	fn skip_known_blocks(&mut self, io: &SyncIo) {
+		let start = match self.blocks.first_pending_head() {
+			Some(start) => start,
+			None => return,
+		};
+		if io.chain().block_status(BlockId::Hash(start)) != BlockStatus::InChain {
+			return;
+		}
+		let mut last = match io.chain().block_header(BlockId::Hash(start)) {
+			Some(header) => header,
+			None => return,
+		};
+		for _ in 0..self.config.max_headers_to_request {
+			match io.chain().block_header(BlockId::Number(last.number() + 1)) {
+				Some(ref next) if next.parent_hash() == last.hash() && !self.blocks.is_head(&next.hash()) => last = next.clone(),
+				_ => break,
+			}
+		}
+		if last.hash() != start {
+			trace!(target: "sync", "Skipping known blocks up to #{} {:?}", last.number(), last.hash());
+			self.blocks.advance_first_head(last.hash());
+		}
+	}
+
+	/// Number of blocks skipped between requested subchain heads.
+	fn subchain_head_skip(&self) -> u64 {
+		// Request max_headers_to_request - 2 headers apart so that
+		// max_headers_to_request would include headers for neighbouring subchains
+		(self.config.max_headers_to_request - 2) as u64
+	}
+
+	/// Number of subchain heads to request this round. Fewer heads are requested when the highest
+	/// block is close, so that they don't overshoot a short chain.
+	fn subchain_heads_request(&self) -> u64 {
+		let size = self.config.subchain_size;
+		match self.highest_block {
+			Some(highest) if highest > self.last_imported_block => {
+				let distance = highest - self.last_imported_block;
+				let skip = self.subchain_head_skip();
+				let needed = (distance + skip) / (skip + 1);
+				cmp::min(size, cmp::max(self.config.min_subchain_size, needed))
+			},
+			_ => size,
+		}
+	}
+
+	/// Number of subchain heads to request. Halved after each useless response this round,
+	/// so that a fork point is probed with smaller requests before giving up.
+	fn subchain_heads_count(&self) -> u64 {
+		let size = self.round_subchain_size;
+		let count = size.checked_shr(self.useless_headers_count as u32).unwrap_or(0);
+		cmp::min(size, cmp::max(count, MIN_HEADERS_TO_REQUEST as u64))
+	}
+
+	/// Blocks missing between the last imported block and the lowest downloaded one that no subchain
+	/// head will download, as (first block number, count). Subchains are downloaded by hash from
+	/// their heads, so once every head is a downloaded block nothing else fills such a gap.
+	fn header_gap(&self) -> Option<(BlockNumber, u64)> {
+		if self.state != State::Blocks || self.header_range_request.is_some() {
+			return None;
+		}
+		if !self.blocks.heads().iter().all(|h| self.blocks.contains(h)) {
+			return None;
+		}
+		let start = self.last_imported_block + 1;
+		match self.blocks.lowest_number() {
+			Some(lowest) if lowest > start => Some((start, cmp::min(lowest - start, self.config.max_headers_to_request as u64))),
+			_ => None,
+		}
+	}
+
+	/// Request `count` headers starting at block `start_number`. The response is imported with
+	/// `import_header_range`.
+	pub fn request_header_range(&mut self, start_number: BlockNumber, count: u64) -> BlockRequest {
+		trace!(target: "sync", "Requesting {} headers from block {} to fill a gap", count, start_number);
+		self.header_range_request = Some((start_number, count));
+		BlockRequest::HeadersByNumber {
+			start_number,
+			count,
+		}
+	}
+
+	fn requests_issued<I: IntoIterator<Item = RequestKey>>(&mut self, keys: I) {
+		let now = Instant::now();
+		self.request_issued_at.extend(keys.into_iter().map(|key| (key, now)));
+	}
+
 	/// Reset collection for a new sync round with given subchain block hashes.
 	pub fn reset_to(&mut self, hashes: Vec<H256>) {
-		self.reset();
+		self.reset(ResetReason::NewSubchains);
 		self.blocks.reset_to(hashes);
-		self.state = State::Blocks;
+		self.set_state(State::Blocks);
+	}
+
+	/// Check the consistency of the downloader state, for tests and debugging.
+	/// Returns a description of the first inconsistency found.
+	pub fn check_invariants(&self) -> Result<(), String> {
+		if self.round_parents.len() > MAX_ROUND_PARENTS {
+			return Err(format!("{} round parents kept, at most {} expected", self.round_parents.len(), MAX_ROUND_PARENTS));
+		}
+		// `start_sync_round` steps back both numbers together, so the last imported block only goes below
+		// the round start if it is moved back some other way.
+		if self.last_imported_block < self.last_round_start {
+			return Err(format!("Last imported block {} is below the round start {}", self.last_imported_block, self.last_round_start));
+		}
+		if (self.state == State::Paused) != self.paused_state.is_some() {
+			return Err(format!("State is {:?} with paused state {:?}", self.state, self.paused_state));
+		}
+		match self.state {
+			State::Idle | State::ChainHead if self.blocks.block_count() != 0 || self.blocks.heads_len() != 0 => {
+				return Err(format!("{} blocks and {} subchain heads held in state {:?}", self.blocks.block_count(), self.blocks.heads_len(), self.state));
+			},
+			State::Idle | State::ChainHead if self.header_range_request.is_some() => {
+				return Err(format!("Header range {:?} requested in state {:?}", self.header_range_request, self.state));
+			},
+			_ => (),
+		}
+		Ok(())
 	}
 
 	/// Returns used heap memory size.
@@ -214,30 +789,201 @@ impl BlockDownloader {
 		self.blocks.heap_size() + self.round_parents.heap_size_of_children()
 	}
 
+	/// Number of a header downloaded this round and not imported yet.
+	pub fn known_header_number(&self, hash: &H256) -> Option<BlockNumber> {
+		self.blocks.block_number(hash)
+	}
+
 	/// Returns best imported block number.
 	pub fn last_imported_block_number(&self) -> BlockNumber {
 		self.last_imported_block
 	}
 
+	/// Estimate the time left until the highest known block is imported, based on the recent import rate.
+	/// Returns `None` if the highest block or the import rate is not known yet.
+	pub fn estimated_time_remaining(&self) -> Option<Duration> {
+		let highest = self.highest_block?;
+		if self.import_samples.len() < MIN_IMPORT_RATE_SAMPLES {
+			return None;
+		}
+		let (first_time, first_block) = *self.import_samples.front()?;
+		let (last_time, last_block) = *self.import_samples.back()?;
+		if last_block <= first_block || last_time <= first_time {
+			return None;
+		}
+		let elapsed = last_time - first_time;
+		let elapsed_ms = elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64;
+		let remaining = highest.saturating_sub(self.last_imported_block);
+		Some(Duration::from_millis(remaining * elapsed_ms / (last_block - first_block)))
+	}
+
+	/// Estimate the number of bytes left to download until the highest known block, based on the average
+	/// amount of data received per imported block. Returns `None` if the highest block is not known or
+	/// no block has been imported yet.
+	pub fn estimated_bytes_remaining(&self) -> Option<u64> {
+		let highest = self.highest_block?;
+		if self.blocks_imported == 0 {
+			return None;
+		}
+		let remaining = highest.saturating_sub(self.last_imported_block);
+		Some(remaining.saturating_mul(self.transfer_stats.total_bytes()) / self.blocks_imported)
+	}
+
+	fn record_import_sample(&mut self, now: Instant) {
+		// Samples taken before a retract do not describe the current progress.
+		if self.import_samples.back().map_or(false, |&(_, n)| n > self.last_imported_block) {
+			self.import_samples.clear();
+		}
+		self.import_samples.push_back((now, self.last_imported_block));
+		if self.import_samples.len() > MAX_IMPORT_RATE_SAMPLES {
+			self.import_samples.pop_front();
+		}
+	}
+
+	/// Returns download progress.
+	pub fn status(&self) -> BlockDownloaderStatus {
+		BlockDownloaderStatus {
+			state: self.state,
+			last_imported_block: self.last_imported_block,
+			highest_block: self.highest_block,
+			imported_this_round: self.imported_this_round,
+			queued_blocks: self.blocks.block_count(),
+		}
+	}
+
+	/// Refuse to download or import blocks with any of the given hashes. Headers containing
+	/// them are rejected as known bad blocks. The set is never pruned, it is up to the caller
+	/// to keep it small.
+	pub fn blacklist(&mut self, hashes: &[H256]) {
+		self.blacklisted.extend(hashes.iter().cloned());
+	}
+
+	/// Only follow chains with block `hash` at height `number`. Headers at that height with any
+	/// other hash are rejected, as are peers sending them.
+	pub fn require_block(&mut self, number: BlockNumber, hash: H256) {
+		self.required_blocks.insert(number, hash);
+	}
+
+	/// Number of block import calls that took less than 1ms, 10ms, 100ms and longer.
+	/// Blocks queued together with their receipts count as a single call.
+	pub fn import_latency_histogram(&self) -> [u64; 4] {
+		self.import_latency
+	}
+
+	fn record_import_latency(&mut self, elapsed: Duration) {
+		let ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+		let bucket = IMPORT_LATENCY_BUCKETS_MS.iter().position(|&limit| ms < limit).unwrap_or(IMPORT_LATENCY_BUCKETS_MS.len());
+		self.import_latency[bucket] += 1;
+	}
+
+	fn import_block(&mut self, chain: &BlockChainClient, block: Unverified) -> Result<H256, EthcoreError> {
+		let started = Instant::now();
+		let result = chain.import_block(block);
+		self.record_import_latency(started.elapsed());
+		result
+	}
+
+	/// What the headers of the last headers response turned out to be.
+	pub fn last_response_breakdown(&self) -> HeadersBreakdown {
+		self.last_response_breakdown
+	}
+
+	/// Hashes of the subchain heads the downloader is working from.
+	pub fn subchain_heads(&self) -> Vec<H256> {
+		self.blocks.heads().to_vec()
+	}
+
+	/// Why the downloader was last reset, `None` if it never was.
+	pub fn last_reset_reason(&self) -> Option<ResetReason> {
+		self.last_reset_reason
+	}
+
+	/// Number of blocks the downloader last had to step back before finding a block in common with its peers.
+	/// `None` if it never had to.
+	pub fn last_reorg_depth(&self) -> Option<u64> {
+		self.last_reorg_depth
+	}
+
+	/// Check if the last `threshold` sync rounds, or more, have not imported any block.
+	pub fn is_stalled(&self, threshold: usize) -> bool {
+		self.rounds_without_progress >= threshold
+	}
+
+	/// Returns the number of bytes received so far for each request type.
+	pub fn transfer_stats(&self) -> TransferStats {
+		self.transfer_stats
+	}
+
 	/// Add new block headers.
-	pub fn import_headers(&mut self, io: &mut SyncIo, r: &Rlp, expected_hash: Option<H256>) -> Result<DownloadAction, BlockDownloaderImportError> {
+	pub fn import_headers(&mut self, io: &mut SyncIo, r: &Rlp, expected_hash: Option<H256>) -> Result<(DownloadAction, ResponseQuality), BlockDownloaderImportError> {
 		let item_count = r.item_count().unwrap_or(0);
-		if self.state == State::Idle {
+		self.transfer_stats.headers_bytes += items_size(r);
+		self.last_response_breakdown = HeadersBreakdown::default();
+		if self.state == State::Idle || self.state == State::Paused {
 			trace!(target: "sync", "Ignored unexpected block headers");
-			return Ok(DownloadAction::None)
+			return Ok((DownloadAction::None, ResponseQuality::Redundant))
 		}
 		if item_count == 0 && (self.state == State::Blocks) {
-			return Err(BlockDownloaderImportError::Invalid);
+			return Err(BlockDownloaderImportError::Invalid(InvalidReason::UnexpectedHash));
+		}
+
+		// A peer echoing a subchain we already hold: the first header is the requested one and follows our
+		// last imported block, and both the first and the last header are already downloaded. Don't decode
+		// the rest of the response. A response only starting with known headers may extend the subchain.
+		if self.state == State::Blocks {
+			let decode = |i| r.at(i).and_then(|header| SyncHeader::from_rlp(header.as_raw().to_vec()));
+			if let (Ok(first), Ok(last)) = (decode(0), decode(item_count - 1)) {
+				let hash = first.header.hash();
+				if expected_hash == Some(hash) && self.blocks.contains(&hash) && *first.header.parent_hash() == self.last_imported_hash
+					&& self.blocks.contains(&last.header.hash()) {
+					trace!(target: "sync", "Skipping {} already downloaded headers", item_count);
+					return self.useless_headers();
+				}
+			}
 		}
 
 		let mut headers = Vec::new();
 		let mut hashes = Vec::new();
 		let mut valid_response = item_count == 0; //empty response is valid
 		let mut any_known = false;
+		let mut previous: Option<(BlockNumber, H256)> = None;
+		let mut infos = Vec::with_capacity(item_count);
 		for i in 0..item_count {
-			let info = SyncHeader::from_rlp(r.at(i)?.as_raw().to_vec())?;
+			let info = r.at(i)
+				.and_then(|header| SyncHeader::from_rlp(header.as_raw().to_vec()))
+				.map_err(|e| {
+					trace!(target: "sync", "Error decoding block header RLP: {:?}", e);
+					BlockDownloaderImportError::Invalid(InvalidReason::BadRlp)
+				})?;
+			infos.push(info);
+		}
+		// Some peers swap two adjacent headers of a subchain. Put them back in order, anything more
+		// out of order is rejected as a broken chain below.
+		let swapped = {
+			let mut descending = (0..infos.len().saturating_sub(1)).filter(|&i| infos[i + 1].header.number() < infos[i].header.number());
+			match (descending.next(), descending.next()) {
+				(Some(i), None) if self.state == State::Blocks => Some(i),
+				_ => None,
+			}
+		};
+		if let Some(i) = swapped {
+			trace!(target: "sync", "Swapping headers {} and {} back in order", infos[i].header.number(), infos[i + 1].header.number());
+			infos.swap(i, i + 1);
+		}
+		for info in infos {
 			let number = BlockNumber::from(info.header.number());
 			let hash = info.header.hash();
+			// Subchain headers are requested without gaps, so each must be the child of the previous one.
+			// Subchain heads are requested with a skip and are not checked.
+			if self.state == State::Blocks {
+				if let Some((previous_number, previous_hash)) = previous {
+					if number != previous_number + 1 || *info.header.parent_hash() != previous_hash {
+						trace!(target: "sync", "Header {} ({:?}) does not follow {} ({:?})", number, hash, previous_number, previous_hash);
+						return Err(BlockDownloaderImportError::Invalid(InvalidReason::BrokenChain));
+					}
+				}
+				previous = Some((number, hash));
+			}
 			// Check if any of the headers matches the hash we requested
 			if !valid_response {
 				if let Some(expected) = expected_hash {
@@ -247,26 +993,52 @@ impl BlockDownloader {
 			any_known = any_known || self.blocks.contains_head(&hash);
 			if self.blocks.contains(&hash) {
 				trace!(target: "sync", "Skipping existing block header {} ({:?})", number, hash);
+				self.last_response_breakdown.already_contained += 1;
+				continue;
+			}
+
+			if self.max_block.map_or(false, |max| number > max) {
+				trace!(target: "sync", "Skipping block header {} ({:?}) above max block", number, hash);
+				self.last_response_breakdown.above_max_block += 1;
 				continue;
 			}
 
+			if self.blacklisted.contains(&hash) {
+				trace!(target: "sync", "Blacklisted block header {} ({:?})", number, hash);
+				self.last_response_breakdown.bad += 1;
+				return Err(BlockDownloaderImportError::Invalid(InvalidReason::KnownBadBlock));
+			}
+
+			if self.required_blocks.get(&number).map_or(false, |required| *required != hash) {
+				trace!(target: "sync", "Block header {} ({:?}) is not the required one", number, hash);
+				self.last_response_breakdown.bad += 1;
+				return Err(BlockDownloaderImportError::Invalid(InvalidReason::WrongFork));
+			}
+
 			if self.highest_block.as_ref().map_or(true, |n| number > *n) {
 				self.highest_block = Some(number);
 			}
 
 			match io.chain().block_status(BlockId::Hash(hash.clone())) {
-				BlockStatus::InChain | BlockStatus::Queued => {
+				status @ BlockStatus::InChain | status @ BlockStatus::Queued => {
 					match self.state {
 						State::Blocks => trace!(target: "sync", "Header already in chain {} ({})", number, hash),
 						_ => trace!(target: "sync", "Header already in chain {} ({}), state = {:?}", number, hash, self.state),
 					}
+					if status == BlockStatus::InChain {
+						self.last_response_breakdown.in_chain += 1;
+					} else {
+						self.last_response_breakdown.queued += 1;
+					}
 					headers.push(info);
 					hashes.push(hash);
 				},
 				BlockStatus::Bad => {
-					return Err(BlockDownloaderImportError::Invalid);
+					self.last_response_breakdown.bad += 1;
+					return Err(BlockDownloaderImportError::Invalid(InvalidReason::KnownBadBlock));
 				},
 				BlockStatus::Unknown => {
+					self.last_response_breakdown.new += 1;
 					headers.push(info);
 					hashes.push(hash);
 				}
@@ -276,50 +1048,164 @@ impl BlockDownloader {
 		// Disable the peer for this syncing round if it gives invalid chain
 		if !valid_response {
 			trace!(target: "sync", "Invalid headers response");
-			return Err(BlockDownloaderImportError::Invalid);
+			return Err(BlockDownloaderImportError::Invalid(InvalidReason::UnexpectedHash));
 		}
 
-		match self.state {
+		let quality = match self.state {
 			State::ChainHead => {
 				if !headers.is_empty() {
-					// TODO: validate heads better. E.g. check that there is enough distance between blocks.
+					let min_head_gap = self.subchain_head_skip() + 1;
+					if headers.windows(2).any(|w| w[1].header.number() < w[0].header.number() + min_head_gap) {
+						trace!(target: "sync", "Subchain heads are less than {} blocks apart", min_head_gap);
+						return Err(BlockDownloaderImportError::Invalid(InvalidReason::HeadsTooClose));
+					}
+					if let Some(from) = self.retract_from.take() {
+						let depth = from.saturating_sub(self.last_imported_block);
+						debug!(target: "sync", "Found common block {} after stepping back {} blocks", self.last_imported_block, depth);
+						self.last_reorg_depth = Some(depth);
+					}
 					trace!(target: "sync", "Received {} subchain heads, proceeding to download", headers.len());
+					// Heads are in ascending order, keep the lowest ones so that the downloaded blocks can be imported.
+					if hashes.len() > self.config.max_heads {
+						debug!(target: "sync", "Keeping {} of {} subchain heads", self.config.max_heads, hashes.len());
+						hashes.truncate(self.config.max_heads);
+					}
 					self.blocks.reset_to(hashes);
+					self.useless_headers_count = 0;
 					self.state = State::Blocks;
-					return Ok(DownloadAction::Reset);
+					return Ok((DownloadAction::Reset, ResponseQuality::Useful));
 				} else {
 					let best = io.chain().chain_info().best_block_number;
 					let oldest_reorg = io.chain().pruning_info().earliest_state;
 					let last = self.last_imported_block;
 					if self.limit_reorg && best > last && (last == 0 || last < oldest_reorg) {
 						trace!(target: "sync", "No common block, disabling peer");
-						return Err(BlockDownloaderImportError::Invalid);
+						return Err(BlockDownloaderImportError::Invalid(InvalidReason::NoCommonBlock));
+
 					}
+					self.useless_headers_count += 1;
+					trace!(target: "sync", "No subchain heads ({} this round)", self.useless_headers_count);
+					ResponseQuality::Useless
 				}
 			},
 			State::Blocks => {
 				let count = headers.len();
 				// At least one of the heades must advance the subchain. Otherwise they are all useless.
 				if count == 0 || !any_known {
-					trace!(target: "sync", "No useful headers");
-					return Err(BlockDownloaderImportError::Useless);
+					return self.useless_headers();
 				}
+				self.useless_headers_count = 0;
 				self.blocks.insert_headers(headers);
 				trace!(target: "sync", "Inserted {} headers", count);
+				ResponseQuality::Useful
+			},
+			_ => {
+				trace!(target: "sync", "Unexpected headers({})", headers.len());
+				ResponseQuality::Redundant
 			},
-			_ => trace!(target: "sync", "Unexpected headers({})", headers.len()),
+		};
+
+		Ok((DownloadAction::None, quality))
+	}
+
+	/// Account for a headers response that did not advance any subchain.
+	fn useless_headers(&mut self) -> Result<(DownloadAction, ResponseQuality), BlockDownloaderImportError> {
+		self.useless_headers_count += 1;
+		trace!(target: "sync", "No useful headers ({} this round)", self.useless_headers_count);
+		if self.useless_headers_count >= self.useless_threshold() {
+			trace!(target: "sync", "Received {} useless responses this round. Resetting sync", self.useless_headers_count);
+			self.reset(ResetReason::UselessHeaders);
+		}
+		Err(BlockDownloaderImportError::Useless)
+	}
+
+	/// Number of useless headers responses tolerated in a round before resetting.
+	fn useless_threshold(&self) -> usize {
+		// A single subchain is only downloaded at the chain head, where peers may legitimately have nothing new.
+		if self.blocks.heads_len() <= 1 {
+			return usize::max_value();
+		}
+		// Far behind the highest block seen every peer should have the requested headers.
+		let far_from_tip = self.highest_block
+			.map_or(false, |highest| highest > self.last_imported_block + self.config.subchain_size);
+		if far_from_tip {
+			MIN_USELESS_HEADERS_PER_ROUND
+		} else {
+			MAX_USELESS_HEADERS_PER_ROUND
+		}
+	}
+
+	/// Header range requested by number and not received yet, as (first block number, count).
+	pub fn header_range_request(&self) -> Option<(BlockNumber, u64)> {
+		self.header_range_request
+	}
+
+	/// Called by peer once it has `count` headers from block `start_number`, requested with `request_header_range`.
+	pub fn import_header_range(&mut self, io: &mut SyncIo, r: &Rlp, start_number: BlockNumber, count: u64) -> Result<(DownloadAction, ResponseQuality), BlockDownloaderImportError> {
+		self.transfer_stats.headers_bytes += items_size(r);
+		self.header_range_request = None;
+		if self.state != State::Blocks {
+			trace!(target: "sync", "Ignored unexpected header range");
+			return Ok((DownloadAction::None, ResponseQuality::Redundant));
+		}
+		if start_number != self.last_imported_block + 1 {
+			trace!(target: "sync", "Ignored header range from {}, last imported block is {}", start_number, self.last_imported_block);
+			return Ok((DownloadAction::None, ResponseQuality::Redundant));
+		}
+		let item_count = r.item_count().unwrap_or(0);
+		if item_count == 0 {
+			return self.useless_headers();
+		}
+		if item_count as u64 > count {
+			trace!(target: "sync", "Received {} headers, requested {}", item_count, count);
+			return Err(BlockDownloaderImportError::Invalid(InvalidReason::BrokenChain));
+		}
+
+		let mut headers = Vec::with_capacity(item_count);
+		let mut previous = (self.last_imported_block, self.last_imported_hash);
+		for i in 0..item_count {
+			let info = r.at(i)
+				.and_then(|header| SyncHeader::from_rlp(header.as_raw().to_vec()))
+				.map_err(|e| {
+					trace!(target: "sync", "Error decoding block header RLP: {:?}", e);
+					BlockDownloaderImportError::Invalid(InvalidReason::BadRlp)
+				})?;
+			let number = BlockNumber::from(info.header.number());
+			let hash = info.header.hash();
+			if number != previous.0 + 1 || *info.header.parent_hash() != previous.1 {
+				trace!(target: "sync", "Header {} ({:?}) does not follow {} ({:?})", number, hash, previous.0, previous.1);
+				return Err(BlockDownloaderImportError::Invalid(InvalidReason::BrokenChain));
+			}
+			if self.blacklisted.contains(&hash) || io.chain().block_status(BlockId::Hash(hash)) == BlockStatus::Bad {
+				return Err(BlockDownloaderImportError::Invalid(InvalidReason::KnownBadBlock));
+			}
+			if self.required_blocks.get(&number).map_or(false, |required| *required != hash) {
+				return Err(BlockDownloaderImportError::Invalid(InvalidReason::WrongFork));
+			}
+			previous = (number, hash);
+			headers.push(info);
 		}
 
-		Ok(DownloadAction::None)
+		trace!(target: "sync", "Inserted {} headers from block {}", headers.len(), start_number);
+		self.useless_headers_count = 0;
+		self.blocks.insert_range_headers(headers);
+		Ok((DownloadAction::None, ResponseQuality::Useful))
 	}
 
 	/// Called by peer once it has new block bodies
-	pub fn import_bodies(&mut self, r: &Rlp) -> Result<(), BlockDownloaderImportError> {
+	pub fn import_bodies(&mut self, r: &Rlp) -> Result<ResponseQuality, BlockDownloaderImportError> {
 		let item_count = r.item_count().unwrap_or(0);
+		self.transfer_stats.bodies_bytes += items_size(r);
 		if item_count == 0 {
+			// At the chain head peers may not have the bodies of the newest blocks yet.
+			if self.at_chain_head() {
+				trace!(target: "sync", "Empty block bodies at the chain head");
+				return Ok(ResponseQuality::Useless);
+			}
 			return Err(BlockDownloaderImportError::Useless);
 		} else if self.state != State::Blocks {
 			trace!(target: "sync", "Ignored unexpected block bodies");
+			Ok(ResponseQuality::Redundant)
 		} else {
 			let mut bodies = Vec::with_capacity(item_count);
 			for i in 0..item_count {
@@ -327,35 +1213,76 @@ impl BlockDownloader {
 				bodies.push(body);
 			}
 
-			if self.blocks.insert_bodies(bodies) != item_count {
-				trace!(target: "sync", "Deactivating peer for giving invalid block bodies");
-				return Err(BlockDownloaderImportError::Invalid);
+			// Check the whole response first, so that a bad response inserts nothing.
+			if !self.blocks.check_bodies(&bodies) || self.blocks.insert_bodies(bodies) != item_count {
+				self.invalid_bodies_count += 1;
+				trace!(target: "sync", "Deactivating peer for giving invalid block bodies ({} this round)", self.invalid_bodies_count);
+				if self.invalid_bodies_count >= MAX_INVALID_BODIES_PER_ROUND {
+					trace!(target: "sync", "Received {} invalid bodies responses this round. Resetting sync", self.invalid_bodies_count);
+					self.reset(ResetReason::InvalidBodies);
+				}
+				return Err(BlockDownloaderImportError::Invalid(InvalidReason::BodiesMismatch));
 			}
+			self.invalid_bodies_count = 0;
+			Ok(ResponseQuality::Useful)
 		}
-		Ok(())
 	}
 
-	/// Called by peer once it has new block bodies
-	pub fn import_receipts(&mut self, _io: &mut SyncIo, r: &Rlp) -> Result<(), BlockDownloaderImportError> {
+	/// Order to request block bodies in. Newest first is only used at the chain head: with several
+	/// subchains nothing can be imported until the bodies of the lowest one are downloaded.
+	fn effective_bodies_order(&self) -> BodiesOrder {
+		if self.bodies_order == BodiesOrder::NewestFirst && self.at_chain_head() {
+			BodiesOrder::NewestFirst
+		} else {
+			BodiesOrder::Chain
+		}
+	}
+
+	/// Whether a single subchain is being downloaded and it holds the highest block seen.
+	fn at_chain_head(&self) -> bool {
+		self.state == State::Blocks
+			&& self.blocks.heads_len() <= 1
+			&& match (self.highest_block, self.blocks.highest_number()) {
+				(Some(highest), Some(downloaded)) => downloaded >= highest,
+				_ => false,
+			}
+	}
+
+	/// Called by peer once it has new block receipts. `requested` are the hashes of the blocks
+	/// whose receipts were asked from the peer. A response with more receipts than requested is
+	/// invalid, a shorter one is accepted as partial: peers may cap their responses, so it is not
+	/// rejected. The caller is expected to have cleared the peer's download (`clear_receipt_download`),
+	/// which makes the receipts that were not delivered requestable again.
+	pub fn import_receipts(&mut self, _io: &mut SyncIo, r: &Rlp, requested: &[H256]) -> Result<(), BlockDownloaderImportError> {
 		let item_count = r.item_count().unwrap_or(0);
+		self.transfer_stats.receipts_bytes += items_size(r);
 		if item_count == 0 {
 			return Err(BlockDownloaderImportError::Useless);
 		}
 		else if self.state != State::Blocks {
 			trace!(target: "sync", "Ignored unexpected block receipts");
 		}
+		else if item_count > requested.len() {
+			trace!(target: "sync", "Deactivating peer for giving {} block receipts, {} were requested", item_count, requested.len());
+			return Err(BlockDownloaderImportError::Invalid(InvalidReason::TooManyReceipts));
+		}
 		else {
 			let mut receipts = Vec::with_capacity(item_count);
 			for i in 0..item_count {
 				let receipt = r.at(i).map_err(|e| {
 					trace!(target: "sync", "Error decoding block receipts RLP: {:?}", e);
-					BlockDownloaderImportError::Invalid
+					BlockDownloaderImportError::Invalid(InvalidReason::BadRlp)
 				})?;
 				receipts.push(receipt.as_raw().to_vec());
 			}
+			// Receipts are matched to blocks by their trie root, so a set that does not hash
+			// to the `receipts_root` of a downloaded header is not inserted.
 			if self.blocks.insert_receipts(receipts) != item_count {
 				trace!(target: "sync", "Deactivating peer for giving invalid block receipts");
-				return Err(BlockDownloaderImportError::Invalid);
+				return Err(BlockDownloaderImportError::Invalid(InvalidReason::ReceiptsMismatch));
+			}
+			if item_count < requested.len() {
+				trace!(target: "sync", "Partial block receipts response, {} of {} delivered", item_count, requested.len());
 			}
 		}
 		Ok(())
@@ -366,10 +1293,16 @@ impl BlockDownloader {
 		trace!(target: "sync", "Starting round (last imported count = {:?}, last started = {}, block = {:?}", self.imported_this_round, self.last_round_start, self.last_imported_block);
 		// Check if need to retract to find the common block. The problem is that the peers still return headers by hash even
 		// from the non-canonical part of the tree. So we also retract if nothing has been imported last round.
+		if self.imported_this_round == Some(0) {
+			self.rounds_without_progress += 1;
+		}
 		let start = self.last_round_start;
 		let start_hash = self.last_round_start_hash;
 		match self.imported_this_round {
 			Some(n) if n == 0 && start > 0 => {
+				if self.limit_reorg && self.retract_from.is_none() {
+					self.retract_from = Some(start);
+				}
 				// nothing was imported last round, step back to a previous block
 				// search parent in last round known parents first
 				if let Some(&(_, p)) = self.round_parents.iter().find(|&&(h, _)| h == start_hash) {
@@ -381,10 +1314,17 @@ impl BlockDownloader {
 					let oldest_reorg = io.chain().pruning_info().earliest_state;
 					if self.limit_reorg && best > start && start < oldest_reorg {
 						debug!(target: "sync", "Could not revert to previous ancient block, last: {} ({})", start, start_hash);
-						self.reset();
+						self.reset(ResetReason::RetractFailed);
 					} else {
 						let n = start - cmp::min(self.retract_step, start);
-						self.retract_step *= 2;
+						self.retract_step = match self.retract_step.checked_mul(2) {
+							Some(step) if step <= self.config.max_retract_step => step,
+							_ => {
+								debug!(target: "sync", "Retract step limit reached, probing {} blocks back", self.config.max_retract_step);
+								self.config.max_retract_step
+							},
+						};
+
 						match io.chain().block_hash(BlockId::Number(n)) {
 							Some(h) => {
 								self.last_imported_block = n;
@@ -393,7 +1333,7 @@ impl BlockDownloader {
 							}
 							None => {
 								debug!(target: "sync", "Could not revert to previous block, last: {} ({})", start, self.last_imported_hash);
-								self.reset();
+								self.reset(ResetReason::RetractFailed);
 							}
 						}
 					}
@@ -401,8 +1341,10 @@ impl BlockDownloader {
 			},
 			_ => {
 				self.retract_step = 1;
+				self.retract_from = None;
 			},
 		}
+		self.round_subchain_size = self.subchain_heads_request();
 		self.last_round_start = self.last_imported_block;
 		self.last_round_start_hash = self.last_imported_hash;
 		self.imported_this_round = None;
@@ -418,88 +1360,277 @@ impl BlockDownloader {
 				}
 			},
 			State::ChainHead => {
-				if num_active_peers < MAX_PARALLEL_SUBCHAIN_DOWNLOAD {
+				if num_active_peers < self.config.max_parallel_subchain_download {
 					// Request subchain headers
 					trace!(target: "sync", "Starting sync with better chain");
-					// Request MAX_HEADERS_TO_REQUEST - 2 headers apart so that
-					// MAX_HEADERS_TO_REQUEST would include headers for neighbouring subchains
 					return Some(BlockRequest::Headers {
 						start: self.last_imported_hash.clone(),
-						count: SUBCHAIN_SIZE,
-						skip: (MAX_HEADERS_TO_REQUEST - 2) as u64,
+						count: self.subchain_heads_count(),
+						skip: self.subchain_head_skip(),
+						subchain: None,
 					});
 				}
 			},
 			State::Blocks => {
 				// check to see if we need to download any block bodies first
-				let needed_bodies = self.blocks.needed_bodies(MAX_BODIES_TO_REQUEST, false);
+				let needed_bodies = self.blocks.needed_bodies(self.config.max_bodies_to_request, false, self.effective_bodies_order());
 				if !needed_bodies.is_empty() {
+					self.requests_issued(needed_bodies.iter().cloned().map(RequestKey::Body));
 					return Some(BlockRequest::Bodies {
 						hashes: needed_bodies,
 					});
 				}
 
 				if self.download_receipts {
-					let needed_receipts = self.blocks.needed_receipts(MAX_RECEPITS_TO_REQUEST, false);
+					let needed_receipts = self.blocks.needed_receipts(self.config.max_receipts_to_request, false);
 					if !needed_receipts.is_empty() {
+						self.requests_issued(needed_receipts.iter().cloned().map(RequestKey::Receipt));
 						return Some(BlockRequest::Receipts {
 							hashes: needed_receipts,
 						});
 					}
 				}
 
+				if self.heap_size() > self.config.memory_limit {
+					trace!(target: "sync", "Downloaded blocks use {} bytes, waiting for import before requesting more headers", self.heap_size());
+					return None;
+				}
+
+				self.skip_known_blocks(io);
+
 				// find subchain to download
-				if let Some((h, count)) = self.blocks.needed_headers(MAX_HEADERS_TO_REQUEST, false) {
+				if let Some((h, count)) = self.blocks.needed_headers(self.config.max_headers_to_request, false) {
+					self.requests_issued(Some(RequestKey::Header(h)));
 					return Some(BlockRequest::Headers {
 						start: h,
 						count: count as u64,
 						skip: 0,
+						subchain: Some(h),
 					});
 				}
+
+				if let Some((start_number, count)) = self.header_gap() {
+					return Some(self.request_header_range(start_number, count));
+				}
 			},
-			State::Complete => (),
+			State::Complete | State::Paused => (),
 		}
 		None
 	}
 
-	/// Checks if there are blocks fully downloaded that can be imported into the blockchain and does the import.
-	pub fn collect_blocks(&mut self, io: &mut SyncIo, allow_out_of_order: bool) -> Result<(), BlockDownloaderImportError> {
-		let mut bad = false;
-		let mut imported = HashSet::new();
-		let blocks = self.blocks.drain();
-		let count = blocks.len();
-		for block_and_receipts in blocks {
-			let block = block_and_receipts.block;
-			let receipts = block_and_receipts.receipts;
+	/// List up to `max` requests that `request_blocks` would issue for the current round, in the same order.
+	/// Does not mark anything as being downloaded and does not advance the state machine.
+	pub fn plan_requests(&self, max: usize) -> Vec<BlockRequest> {
+		let mut plan = Vec::new();
+		match self.state {
+			State::ChainHead => {
+				if max > 0 {
+					plan.push(BlockRequest::Headers {
+						start: self.last_imported_hash.clone(),
+						count: self.subchain_heads_count(),
+						skip: self.subchain_head_skip(),
+						subchain: None,
+					});
+				}
+			},
+			State::Blocks => {
+				let mut planned_bodies = HashSet::new();
+				while plan.len() < max {
+					let hashes = self.blocks.pending_bodies(self.config.max_bodies_to_request, &planned_bodies, self.effective_bodies_order());
+					if hashes.is_empty() {
+						break;
+					}
+					planned_bodies.extend(hashes.iter().cloned());
+					plan.push(BlockRequest::Bodies { hashes });
+				}
 
-			let h = block.header.hash();
-			let number = block.header.number();
-			let parent = *block.header.parent_hash();
+				if self.download_receipts {
+					let mut planned_roots = HashSet::new();
+					while plan.len() < max {
+						let receipts = self.blocks.pending_receipts(self.config.max_receipts_to_request, &planned_roots);
+						if receipts.is_empty() {
+							break;
+						}
+						planned_roots.extend(receipts.iter().map(|&(_, root)| root));
+						plan.push(BlockRequest::Receipts {
+							hashes: receipts.into_iter().map(|(h, _)| h).collect(),
+						});
+					}
+				}
 
-			if self.target_hash.as_ref().map_or(false, |t| t == &h) {
-				self.state = State::Complete;
-				trace!(target: "sync", "Sync target reached");
-				return Ok(());
-			}
+				if self.heap_size() > self.config.memory_limit {
+					return plan;
+				}
+
+				let count = self.config.max_headers_to_request as u64;
+				let remaining = max - plan.len();
+				plan.extend(self.blocks.pending_headers().into_iter().take(remaining).map(|start| BlockRequest::Headers {
+					start,
+					count,
+					skip: 0,
+					subchain: Some(start),
+				}));
+
+				if plan.len() < max {
+					if let Some((start_number, count)) = self.header_gap() {
+						plan.push(BlockRequest::HeadersByNumber { start_number, count });
+					}
+				}
+			},
+			State::Idle | State::Complete | State::Paused => (),
+		}
+		plan
+	}
+
+	/// Lists the downloaded blocks that the next `collect_blocks` call would import, in import order.
+	pub fn peek_ready<'a>(&'a self) -> impl Iterator<Item = (&'a H256, BlockNumber)> + 'a {
+		self.blocks.ready_blocks()
+	}
+
+	/// Distribute the requests of the current round among idle peers, at most one request per peer.
+	/// Peers with requests in flight are left alone and count towards the limit of peers downloading
+	/// in parallel. The assigned requests are marked as being downloaded, so that no subchain, body
+	/// or receipt is assigned twice. Nothing is assigned before `request_blocks` starts a round.
+	pub fn assign_work(&mut self, peers: &[PeerId], per_peer_inflight: &HashMap<PeerId, usize>) -> Vec<(PeerId, BlockRequest)> {
+		let (busy, idle): (Vec<PeerId>, Vec<PeerId>) = peers.iter().cloned()
+			.partition(|peer| per_peer_inflight.get(peer).map_or(false, |&n| n > 0));
+		let slots = self.config.max_parallel_subchain_download.saturating_sub(busy.len());
+		let plan = self.plan_requests(cmp::min(idle.len(), slots));
+
+		let assigned: Vec<(PeerId, BlockRequest)> = idle.into_iter().zip(plan).collect();
+		for &(peer, ref request) in &assigned {
+			trace!(target: "sync", "Assigning {:?} to peer {}", request, peer);
+			self.mark_requested(request);
+		}
+		assigned
+	}
+
+	/// Mark the data of a request returned by `plan_requests` as being downloaded.
+	fn mark_requested(&mut self, request: &BlockRequest) {
+		match *request {
+			BlockRequest::Headers { ref start, .. } => {
+				if self.state == State::Blocks {
+					self.blocks.mark_header_download(start);
+					self.requests_issued(Some(RequestKey::Header(*start)));
+				}
+			},
+			BlockRequest::Bodies { ref hashes } => {
+				self.blocks.mark_body_download(hashes);
+				self.requests_issued(hashes.iter().cloned().map(RequestKey::Body));
+			},
+			BlockRequest::Receipts { ref hashes } => {
+				self.blocks.mark_receipt_download(hashes);
+				self.requests_issued(hashes.iter().cloned().map(RequestKey::Receipt));
+			},
+			BlockRequest::HeadersByNumber { start_number, count } => {
+				self.header_range_request = Some((start_number, count));
+			},
+		}
+	}
+
+	/// Checks if there are blocks fully downloaded that can be imported into the blockchain and does the import.
+	pub fn collect_blocks(&mut self, io: &mut SyncIo, allow_out_of_order: bool) -> Result<(), BlockDownloaderImportError> {
+		self.collect_blocks_with(io, allow_out_of_order, &mut |_, _| {})
+	}
+
+	/// Same as `collect_blocks`, but calls `on_imported` for every block that has been queued for import.
+	/// Blocks that turn out to be already in the chain or in the queue are not reported.
+	pub fn collect_blocks_with(&mut self, io: &mut SyncIo, allow_out_of_order: bool, on_imported: &mut FnMut(&H256, BlockNumber)) -> Result<(), BlockDownloaderImportError> {
+		self.collect_blocks_detailed(io, allow_out_of_order, on_imported).result
+	}
+
+	/// Same as `collect_blocks_with`, but also reports downloaded blocks that were dropped
+	/// because the import stopped before reaching them.
+	pub fn collect_blocks_detailed(&mut self, io: &mut SyncIo, allow_out_of_order: bool, on_imported: &mut FnMut(&H256, BlockNumber)) -> CollectOutcome {
+		if self.state == State::Paused {
+			return CollectOutcome { result: Ok(()), abandoned: Vec::new(), imported: 0, elapsed: Duration::from_secs(0) };
+		}
+		let blocks = self.blocks.drain();
+		self.import_blocks(io, blocks, allow_out_of_order, on_imported)
+	}
+
+	/// Import drained blocks into the chain.
+	fn import_blocks(&mut self, io: &mut SyncIo, blocks: Vec<BlockAndReceipts>, allow_out_of_order: bool, on_imported: &mut FnMut(&H256, BlockNumber)) -> CollectOutcome {
+		let started = Instant::now();
+		let mut bad = false;
+		let mut imported = HashSet::new();
+		let mut known = 0;
+		let count = blocks.len();
 
-			let result = if let Some(receipts) = receipts {
-				io.chain().queue_ancient_block(block, receipts)
+		let mut target_reached = false;
+		let mut headers = Vec::with_capacity(count);
+		let mut new_blocks = Vec::new();
+		let mut ancient_blocks = Vec::new();
+		for block_and_receipts in blocks {
+			let block = block_and_receipts.block;
+			let h = block.header.hash();
+			if self.targets.last().map_or(false, |t| t == &h) {
+				target_reached = true;
+				break;
+			}
+			if self.blacklisted.contains(&h) {
+				debug!(target: "sync", "Blacklisted block {:?}", h);
+				bad = true;
+				break;
+			}
+			headers.push((h, block.header.number(), *block.header.parent_hash()));
+			// Ancient blocks are queued first, so once a block is imported as new the rest follow it.
+			let ancient_receipts = if self.import_ancient && new_blocks.is_empty() {
+				match block_and_receipts.receipts {
+					Some(receipts) => Some(receipts),
+					None if block.header.transactions_root() == &KECCAK_NULL_RLP => Some(RlpStream::new_list(0).out()),
+					None => None,
+				}
 			} else {
-				io.chain().import_block(block)
+				None
+			};
+			match ancient_receipts {
+				Some(receipts) => ancient_blocks.push((block, receipts)),
+				None => new_blocks.push(block),
+			}
+		}
+
+		// Ancient blocks are queued in a single call, which stops at the first failure.
+		// Others are imported one by one so that nothing is imported past the first failure.
+		let to_import = headers.len();
+		let chain = io.chain();
+		let mut ancient_results = if ancient_blocks.is_empty() {
+			Vec::new().into_iter()
+		} else {
+			let started = Instant::now();
+			let results = chain.queue_ancient_block_batch(ancient_blocks);
+			self.record_import_latency(started.elapsed());
+			results.into_iter()
+		};
+		let mut new_blocks = new_blocks.into_iter();
+
+		// Drained blocks form a chain, so only the first one can have a parent missing from the chain.
+		let mut processed = 0;
+		for &(h, number, parent) in &headers {
+			let result = match ancient_results.next() {
+				Some(result) => result,
+				None => match new_blocks.next() {
+					Some(block) => self.import_block(chain, block),
+					None => break,
+				},
 			};
 
 			match result {
 				Err(EthcoreError(EthcoreErrorKind::Import(ImportErrorKind::AlreadyInChain), _)) => {
 					trace!(target: "sync", "Block already in chain {:?}", h);
+					known += 1;
 					self.block_imported(&h, number, &parent);
 				},
 				Err(EthcoreError(EthcoreErrorKind::Import(ImportErrorKind::AlreadyQueued), _)) => {
 					trace!(target: "sync", "Block already queued {:?}", h);
+					known += 1;
 					self.block_imported(&h, number, &parent);
 				},
 				Ok(_) => {
 					trace!(target: "sync", "Block queued {:?}", h);
+					on_imported(&h, number);
 					imported.insert(h.clone());
+
 					self.block_imported(&h, number, &parent);
 				},
 				Err(EthcoreError(EthcoreErrorKind::Block(BlockError::UnknownParent(_)), _)) if allow_out_of_order => {
@@ -523,20 +1654,49 @@ impl BlockDownloader {
 					break;
 				}
 			}
+			processed += 1;
+		}
+
+		let outcome = |result, abandoned| CollectOutcome { result, abandoned, imported: imported.len(), elapsed: started.elapsed() };
+
+		if target_reached && processed == to_import {
+			self.state = State::Complete;
+			trace!(target: "sync", "Sync target reached");
+			return outcome(Ok(()), Vec::new());
+		}
+
+		let abandoned: Vec<H256> = headers[processed..].iter().map(|&(h, _, _)| h).collect();
+		if !abandoned.is_empty() {
+			trace!(target: "sync", "Abandoned {} downloaded blocks", abandoned.len());
+		}
+
+		trace!(target: "sync", "Imported {} of {}, {} already known", imported.len(), count, known);
+		let progress = if self.config.count_known_blocks { imported.len() + known } else { imported.len() };
+		self.imported_this_round = Some(self.imported_this_round.unwrap_or(0) + progress);
+		self.blocks_imported += imported.len() as u64;
+		if progress > 0 {
+			self.rounds_without_progress = 0;
+		}
+		if count > 0 {
+			self.record_import_sample(Instant::now());
+		}
+
+		if self.max_block.map_or(false, |max| self.last_imported_block >= max) {
+			self.state = State::Complete;
+			trace!(target: "sync", "Max block reached");
+			return outcome(Ok(()), abandoned);
 		}
-		trace!(target: "sync", "Imported {} of {}", imported.len(), count);
-		self.imported_this_round = Some(self.imported_this_round.unwrap_or(0) + imported.len());
 
 		if bad {
-			return Err(BlockDownloaderImportError::Invalid);
+			return outcome(Err(BlockDownloaderImportError::Invalid(InvalidReason::BadBlock)), abandoned);
 		}
 
 		if self.blocks.is_empty() {
 			// complete sync round
 			trace!(target: "sync", "Sync round complete");
-			self.reset();
+			self.reset(ResetReason::RoundComplete);
 		}
-		Ok(())
+		outcome(Ok(()), abandoned)
 	}
 
 	fn block_imported(&mut self, hash: &H256, number: BlockNumber, parent: &H256) {
@@ -546,7 +1706,1963 @@ impl BlockDownloader {
 		if self.round_parents.len() > MAX_ROUND_PARENTS {
 			self.round_parents.pop_front();
 		}
+		if self.targets.contains(hash) && !self.reached_targets.contains(hash) {
+			trace!(target: "sync", "Intermediate sync target reached {:?}", hash);
+			self.reached_targets.push(hash.clone());
+		}
 	}
+
 }
 
-//TODO: module tests
+/// Total raw size of the items of an RLP list.
+fn items_size(r: &Rlp) -> u64 {
+	r.iter().map(|item| item.as_raw().len() as u64).sum()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ethcore::client::{TestBlockChainClient, EachBlockWith, BlockChainClient};
+	use ethcore::header::Header as BlockHeader;
+	use parking_lot::RwLock;
+	use rlp::RlpStream;
+	use triehash_ethereum::ordered_trie_root;
+	use tests::helpers::{TestIo, TestPacket};
+	use tests::snapshot::TestSnapshotService;
+
+	fn headers_rlp(client: &BlockChainClient, from: BlockNumber, count: u64) -> Vec<u8> {
+		headers_rlp_at(client, &(from..(from + count)).collect::<Vec<_>>())
+	}
+
+	fn bodies_rlp(client: &BlockChainClient, numbers: &[BlockNumber]) -> Vec<u8> {
+		let mut stream = RlpStream::new_list(numbers.len());
+		for n in numbers {
+			stream.append_raw(&client.block_body(BlockId::Number(*n)).unwrap().into_inner(), 1);
+		}
+		stream.out()
+	}
+
+	fn headers_rlp_at(client: &BlockChainClient, numbers: &[BlockNumber]) -> Vec<u8> {
+		let mut stream = RlpStream::new_list(numbers.len());
+		for n in numbers {
+			stream.append_raw(&client.block_header(BlockId::Number(*n)).unwrap().into_inner(), 1);
+		}
+		stream.out()
+	}
+
+	fn import_headers(downloader: &mut BlockDownloader, io: &mut SyncIo, remote: &BlockChainClient, from: BlockNumber, count: u64) -> Result<(DownloadAction, ResponseQuality), BlockDownloaderImportError> {
+		let bytes = headers_rlp(remote, from, count);
+		let expected_hash = remote.block_hash(BlockId::Number(from));
+		downloader.import_headers(io, &Rlp::new(&bytes), expected_hash)
+	}
+
+	/// Local chain the downloader under test imports into, with the services its IO needs.
+	struct TestEnv {
+		chain: TestBlockChainClient,
+		snapshot_service: TestSnapshotService,
+		queue: RwLock<VecDeque<TestPacket>>,
+	}
+
+	impl TestEnv {
+		fn new() -> Self {
+			TestEnv {
+				chain: TestBlockChainClient::new(),
+				snapshot_service: TestSnapshotService::new(),
+				queue: RwLock::new(VecDeque::new()),
+			}
+		}
+
+		fn io(&self) -> TestIo<TestBlockChainClient> {
+			TestIo::new(&self.chain, &self.snapshot_service, &self.queue, None)
+		}
+	}
+
+	#[test]
+	fn request_bodies_respects_configured_limit() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(100, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut requested = Vec::new();
+		for max_bodies in &[MAX_BODIES_TO_REQUEST, MAX_BODIES_TO_REQUEST * 2] {
+			let config = BlockDownloaderConfig { max_bodies_to_request: *max_bodies, ..Default::default() };
+			let mut downloader = BlockDownloader::new_with_config(false, &genesis, 0, config);
+			downloader.reset_to(vec![genesis]);
+			match downloader.request_blocks(&mut io, 0) {
+				Some(BlockRequest::Headers { start, count, skip, .. }) => {
+					assert_eq!(start, genesis);
+					assert_eq!(count, MAX_HEADERS_TO_REQUEST as u64);
+					assert_eq!(skip, 0);
+				},
+				_ => panic!("expected a headers request"),
+			}
+			import_headers(&mut downloader, &mut io, &remote, 0, 100).unwrap();
+			match downloader.request_blocks(&mut io, 0) {
+				Some(BlockRequest::Bodies { hashes }) => requested.push(hashes.len()),
+				_ => panic!("expected a bodies request"),
+			}
+		}
+		assert_eq!(requested, vec![MAX_BODIES_TO_REQUEST, MAX_BODIES_TO_REQUEST * 2]);
+	}
+
+	#[test]
+	fn status_reflects_imported_headers() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(20, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		let status = downloader.status();
+		assert_eq!(status.state, State::Idle);
+		assert_eq!(status.highest_block, None);
+		assert_eq!(status.queued_blocks, 0);
+
+		downloader.reset_to(vec![genesis]);
+		import_headers(&mut downloader, &mut io, &remote, 0, 10).unwrap();
+		let status = downloader.status();
+		assert_eq!(status.state, State::Blocks);
+		assert_eq!(status.last_imported_block, 0);
+		assert_eq!(status.highest_block, Some(9));
+		assert_eq!(status.imported_this_round, None);
+		assert_eq!(status.queued_blocks, 10);
+
+		// The subchain head is now block 9, so the next response starts there.
+		import_headers(&mut downloader, &mut io, &remote, 9, 11).unwrap();
+		let status = downloader.status();
+		assert_eq!(status.highest_block, Some(19));
+		assert_eq!(status.queued_blocks, 20);
+	}
+
+	#[test]
+	fn retract_step_is_capped() {
+		let env = TestEnv::new();
+		env.chain.add_blocks(100, EachBlockWith::Nothing);
+		let best = (&env.chain as &BlockChainClient).block_hash(BlockId::Number(100)).unwrap();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::with_unlimited_reorg(false, &best, 100);
+		downloader.config.max_retract_step = 4;
+		let mut last = downloader.last_imported_block;
+		for _ in 0..10 {
+			// nothing imported last round
+			downloader.imported_this_round = Some(0);
+			downloader.start_sync_round(&mut io);
+			assert_eq!(downloader.state, State::ChainHead);
+			assert!(downloader.retract_step <= 4);
+			assert!(last - downloader.last_imported_block <= 4);
+			last = downloader.last_imported_block;
+		}
+		assert_eq!(downloader.retract_step, 4);
+	}
+
+	#[test]
+	fn expired_requests_become_requestable_again() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(100, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.request_blocks(&mut io, 0);
+		import_headers(&mut downloader, &mut io, &remote, 0, 100).unwrap();
+		downloader.clear_header_download(&genesis);
+
+		let first = match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Bodies { hashes }) => hashes,
+			_ => panic!("expected a bodies request"),
+		};
+		match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Bodies { ref hashes }) => assert!(hashes.iter().all(|h| !first.contains(h))),
+			_ => panic!("expected a bodies request"),
+		}
+		assert!(first.iter().all(|h| downloader.is_downloading(h)));
+
+		let timeout = Duration::from_secs(30);
+		assert!(downloader.expire_requests_at(Instant::now(), timeout).is_empty());
+
+		let freed = downloader.expire_requests_at(Instant::now() + timeout, timeout);
+		assert_eq!(freed.len(), MAX_BODIES_TO_REQUEST * 2);
+		assert!(first.iter().all(|h| freed.contains(h) && !downloader.is_downloading(h)));
+		assert!(downloader.request_issued_at.is_empty());
+
+		match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Bodies { hashes }) => assert_eq!(hashes, first),
+			_ => panic!("expected a bodies request"),
+		}
+	}
+
+	#[test]
+	fn collect_blocks_reports_each_imported_block() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let first = (&remote as &BlockChainClient).block_hash(BlockId::Number(1)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![first]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 10).unwrap();
+
+		let mut reported = Vec::new();
+		downloader.collect_blocks_with(&mut io, false, &mut |hash, number| reported.push((*hash, number))).unwrap();
+
+		let expected: Vec<_> = (1..11)
+			.map(|n| ((&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap(), n))
+			.collect();
+		assert_eq!(reported, expected);
+		assert_eq!(downloader.last_imported_block_number(), 10);
+	}
+
+	#[test]
+	fn collect_blocks_does_not_report_blocks_already_in_chain() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let hash = |n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+
+		let env = TestEnv::new();
+		env.chain.add_blocks(3, EachBlockWith::Nothing);
+		env.chain.reject_known_blocks.store(true, ::std::sync::atomic::Ordering::Relaxed);
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![hash(1)]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 10).unwrap();
+
+		let mut reported = Vec::new();
+		downloader.collect_blocks_with(&mut io, false, &mut |hash, number| reported.push((*hash, number))).unwrap();
+
+		// Blocks 1 to 3 are imported as already in chain.
+		let expected: Vec<_> = (4..11).map(|n| (hash(n), n)).collect();
+		assert_eq!(reported, expected);
+		assert_eq!(downloader.last_imported_block_number(), 10);
+	}
+
+	#[test]
+	fn import_headers_reports_invalid_reason() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		env.chain.add_blocks(10, EachBlockWith::Uncle);
+		let mut io = env.io();
+
+		// Malformed header.
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		let mut stream = RlpStream::new_list(1);
+		stream.append(&1u8);
+		let bytes = stream.out();
+		assert_eq!(
+			downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(genesis)).err(),
+			Some(BlockDownloaderImportError::Invalid(InvalidReason::BadRlp))
+		);
+
+		// Response that does not contain the requested header.
+		let bytes = headers_rlp(&remote, 1, 5);
+		assert_eq!(
+			downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(genesis)).err(),
+			Some(BlockDownloaderImportError::Invalid(InvalidReason::UnexpectedHash))
+		);
+
+		// Empty response while downloading subchains.
+		let bytes = RlpStream::new_list(0).out();
+		assert_eq!(
+			downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(genesis)).err(),
+			Some(BlockDownloaderImportError::Invalid(InvalidReason::UnexpectedHash))
+		);
+
+		// Peer has nothing in common with our better chain.
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Headers { .. }) => assert_eq!(downloader.state, State::ChainHead),
+			_ => panic!("expected a headers request"),
+		}
+		assert_eq!(
+			downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(genesis)).err(),
+			Some(BlockDownloaderImportError::Invalid(InvalidReason::NoCommonBlock))
+		);
+
+		// Valid response is still accepted.
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		assert!(import_headers(&mut downloader, &mut io, &remote, 0, 5).is_ok());
+	}
+
+	#[test]
+	fn estimates_time_remaining_from_import_rate() {
+		let genesis = TestBlockChainClient::new().genesis_hash;
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		let start = Instant::now();
+		assert_eq!(downloader.estimated_time_remaining(), None);
+
+		// 100 blocks per second
+		for i in 0..10 {
+			downloader.last_imported_block = i * 100;
+			downloader.record_import_sample(start + Duration::from_secs(i));
+		}
+		assert_eq!(downloader.estimated_time_remaining(), None, "highest block is not known");
+
+		downloader.highest_block = Some(5900);
+		let eta = downloader.estimated_time_remaining().unwrap();
+		assert!(eta >= Duration::from_secs(49) && eta <= Duration::from_secs(51), "unexpected estimate {:?}", eta);
+
+		// older samples fall out of the window
+		for i in 10..30 {
+			downloader.last_imported_block = 900 + (i - 9) * 10;
+			downloader.record_import_sample(start + Duration::from_secs(i));
+		}
+		assert_eq!(downloader.import_samples.len(), MAX_IMPORT_RATE_SAMPLES);
+		let eta = downloader.estimated_time_remaining().unwrap();
+		assert!(eta >= Duration::from_secs(479) && eta <= Duration::from_secs(481), "unexpected estimate {:?}", eta);
+	}
+
+	#[test]
+	fn resumes_from_checkpoint() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let first = (&remote as &BlockChainClient).block_hash(BlockId::Number(1)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![first]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 5).unwrap();
+		downloader.collect_blocks(&mut io, false).unwrap();
+		assert_eq!(downloader.last_imported_block_number(), 5);
+
+		let checkpoint = downloader.checkpoint();
+		let serialized = ::serde_json::to_string(&checkpoint).unwrap();
+		let restored: DownloaderCheckpoint = ::serde_json::from_str(&serialized).unwrap();
+		assert_eq!(restored, checkpoint);
+
+		let mut resumed = BlockDownloader::from_checkpoint(BlockSet::NewBlocks, &restored);
+		assert_eq!(resumed.last_imported_block_number(), 5);
+		assert_eq!(resumed.status().highest_block, Some(5));
+
+		let expected = downloader.request_blocks(&mut io, 0);
+		assert!(expected.is_some());
+		assert_eq!(resumed.request_blocks(&mut io, 0), expected);
+	}
+
+	#[test]
+	fn completes_at_last_of_several_targets() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let hash = |n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.set_targets(vec![hash(4), hash(8)]);
+		downloader.reset_to(vec![hash(1)]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 10).unwrap();
+		downloader.collect_blocks(&mut io, false).unwrap();
+
+		assert!(downloader.is_complete());
+		assert_eq!(downloader.reached_targets(), &[hash(4)]);
+		assert_eq!(downloader.last_imported_block_number(), 7);
+	}
+
+	#[test]
+	fn subchain_heads_count_shrinks_after_useless_responses() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(20, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		let empty = RlpStream::new_list(0).out();
+		let mut counts = Vec::new();
+		for _ in 0..6 {
+			match downloader.request_blocks(&mut io, 0) {
+				Some(BlockRequest::Headers { count, .. }) => counts.push(count),
+				_ => panic!("expected a headers request"),
+			}
+			// the peer has no subchain heads to give
+			assert_eq!(
+				downloader.import_headers(&mut io, &Rlp::new(&empty), Some(genesis)),
+				Ok((DownloadAction::None, ResponseQuality::Useless))
+			);
+		}
+		// halved after each useless response, down to the minimum request size
+		let expected: Vec<_> = (0..6).map(|i| cmp::max(SUBCHAIN_SIZE >> i, MIN_HEADERS_TO_REQUEST as u64)).collect();
+		assert_eq!(counts, expected);
+		assert_eq!(counts.last(), Some(&(MIN_HEADERS_TO_REQUEST as u64)));
+		assert_eq!(downloader.state, State::ChainHead);
+
+		// a useful response restores the full request size
+		import_headers(&mut downloader, &mut io, &remote, 0, 1).unwrap();
+		assert_eq!(downloader.state, State::Blocks);
+		assert_eq!(downloader.subchain_heads_count(), SUBCHAIN_SIZE);
+	}
+
+	#[test]
+	fn cancel_subchain_keeps_other_subchains() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(100, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let hash = |n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis, hash(50)]);
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::Headers { start: genesis, count: 128, skip: 0, subchain: Some(genesis) }));
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::Headers { start: hash(50), count: 128, skip: 0, subchain: Some(hash(50)) }));
+		import_headers(&mut downloader, &mut io, &remote, 50, 10).unwrap();
+		assert_eq!(downloader.status().queued_blocks, 10);
+
+		assert!(!downloader.cancel_subchain(&hash(50)), "subchain head has moved to the last downloaded header");
+		assert!(downloader.cancel_subchain(&hash(59)));
+		assert!(!downloader.cancel_subchain(&hash(59)));
+		assert_eq!(downloader.status().queued_blocks, 0);
+		assert_eq!(downloader.blocks.heads_len(), 1);
+
+		downloader.clear_header_download(&genesis);
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::Headers { start: genesis, count: 128, skip: 0, subchain: Some(genesis) }));
+	}
+
+	#[test]
+	fn rejects_bunched_subchain_heads() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(300, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		let skip = match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Headers { skip, .. }) => skip,
+			_ => panic!("expected a headers request"),
+		};
+		assert_eq!(downloader.state, State::ChainHead);
+
+		let bytes = headers_rlp_at(&remote, &[0, 10, 20]);
+		assert_eq!(
+			downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(genesis)).err(),
+			Some(BlockDownloaderImportError::Invalid(InvalidReason::HeadsTooClose))
+		);
+		assert_eq!(downloader.state, State::ChainHead);
+
+		let bytes = headers_rlp_at(&remote, &[0, skip + 1, 2 * (skip + 1)]);
+		match downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(genesis)) {
+			Ok((DownloadAction::Reset, ResponseQuality::Useful)) => assert_eq!(downloader.state, State::Blocks),
+			_ => panic!("expected transition to Blocks state"),
+		}
+	}
+
+	#[test]
+	fn resets_after_repeated_invalid_bodies() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.request_blocks(&mut io, 0);
+		import_headers(&mut downloader, &mut io, &remote, 0, 10).unwrap();
+
+		// An empty body matches none of the downloaded headers.
+		let mut stream = RlpStream::new_list(1);
+		stream.begin_list(2).begin_list(0);
+		stream.begin_list(0);
+		let invalid = stream.out();
+
+		// A single bad batch is reported but does not reset the round.
+		assert_eq!(downloader.import_bodies(&Rlp::new(&invalid)), Err(BlockDownloaderImportError::Invalid(InvalidReason::BodiesMismatch)));
+		assert_eq!(downloader.state, State::Blocks);
+		assert_eq!(downloader.invalid_bodies_count, 1);
+
+		// A valid batch clears the count.
+		downloader.import_bodies(&Rlp::new(&bodies_rlp(&remote, &[1]))).unwrap();
+		assert_eq!(downloader.invalid_bodies_count, 0);
+
+		for _ in 1..MAX_INVALID_BODIES_PER_ROUND {
+			assert_eq!(downloader.import_bodies(&Rlp::new(&invalid)), Err(BlockDownloaderImportError::Invalid(InvalidReason::BodiesMismatch)));
+			assert_eq!(downloader.state, State::Blocks);
+		}
+		assert_eq!(downloader.import_bodies(&Rlp::new(&invalid)), Err(BlockDownloaderImportError::Invalid(InvalidReason::BodiesMismatch)));
+		assert_eq!(downloader.state, State::Idle);
+		assert_eq!(downloader.invalid_bodies_count, 0);
+		assert_eq!(downloader.last_reset_reason(), Some(ResetReason::InvalidBodies));
+	}
+
+	#[test]
+	fn records_reset_reason() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let first = (&remote as &BlockChainClient).block_hash(BlockId::Number(1)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		assert_eq!(downloader.last_reset_reason(), None);
+
+		downloader.reset_to(vec![first]);
+		assert_eq!(downloader.last_reset_reason(), Some(ResetReason::NewSubchains));
+
+		import_headers(&mut downloader, &mut io, &remote, 1, 10).unwrap();
+		downloader.collect_blocks(&mut io, false).unwrap();
+		assert_eq!(downloader.last_reset_reason(), Some(ResetReason::RoundComplete));
+
+		downloader.reset(ResetReason::Requested);
+		assert_eq!(downloader.last_reset_reason(), Some(ResetReason::Requested));
+	}
+
+	#[test]
+	fn plan_matches_issued_requests() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(100, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(true, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		assert_eq!(downloader.plan_requests(10), vec![BlockRequest::Headers { start: genesis, count: 128, skip: 0, subchain: Some(genesis) }]);
+		downloader.request_blocks(&mut io, 0);
+		import_headers(&mut downloader, &mut io, &remote, 0, 100).unwrap();
+
+		let plan = downloader.plan_requests(usize::max_value());
+		assert!(plan.len() > 2);
+		assert_eq!(downloader.plan_requests(2), &plan[..2]);
+		// Planning does not mark anything as being downloaded.
+		assert_eq!(downloader.plan_requests(usize::max_value()), plan);
+
+		let mut issued = Vec::new();
+		while let Some(request) = downloader.request_blocks(&mut io, 0) {
+			issued.push(request);
+		}
+		assert_eq!(issued, plan);
+		assert!(downloader.plan_requests(usize::max_value()).is_empty());
+	}
+
+	#[test]
+	fn ancient_blocks_batch_stops_at_first_failure() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(128, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let first = (&remote as &BlockChainClient).block_hash(BlockId::Number(1)).unwrap();
+
+		let env = TestEnv::new();
+		*env.chain.queue_full_above.write() = Some(64);
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::with_unlimited_reorg(true, &genesis, 0);
+		downloader.reset_to(vec![first]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 128).unwrap();
+		let outcome = downloader.collect_blocks_detailed(&mut io, false, &mut |_, _| {});
+
+		// All the blocks are handed to the client in a single call, which stops at the block that found the queue full.
+		assert_eq!(env.chain.ancient_queue_calls.load(::std::sync::atomic::Ordering::Relaxed), 1);
+		assert_eq!(outcome.abandoned.len(), 64);
+		assert_eq!(downloader.last_imported_block_number(), 64);
+		assert_eq!((&env.chain as &BlockChainClient).chain_info().best_block_number, 64);
+	}
+
+	#[test]
+	fn old_blocks_are_queued_as_ancient_without_receipts() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let first = (&remote as &BlockChainClient).block_hash(BlockId::Number(1)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::with_unlimited_reorg(true, &genesis, 0);
+		downloader.set_download_receipts(false);
+		downloader.reset_to(vec![first]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 10).unwrap();
+		downloader.collect_blocks(&mut io, false).unwrap();
+
+		assert_eq!(env.chain.ancient_queue_calls.load(::std::sync::atomic::Ordering::Relaxed), 1);
+		assert_eq!(downloader.last_imported_block_number(), 10);
+	}
+
+	#[test]
+	fn old_blocks_with_transactions_are_imported_as_new_without_receipts() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+		let first = (&remote as &BlockChainClient).block_hash(BlockId::Number(1)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::with_unlimited_reorg(true, &genesis, 0);
+		downloader.set_download_receipts(false);
+		downloader.reset_to(vec![first]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 10).unwrap();
+		downloader.import_bodies(&Rlp::new(&bodies_rlp(&remote, &(1..11).collect::<Vec<_>>()))).unwrap();
+		downloader.collect_blocks(&mut io, false).unwrap();
+
+		assert_eq!(env.chain.ancient_queue_calls.load(::std::sync::atomic::Ordering::Relaxed), 0);
+		assert_eq!(downloader.last_imported_block_number(), 10);
+		assert_eq!((&env.chain as &BlockChainClient).block_hash(BlockId::Number(10)), (&remote as &BlockChainClient).block_hash(BlockId::Number(10)));
+	}
+
+	#[test]
+	fn reports_blocks_abandoned_on_full_queue() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let first = (&remote as &BlockChainClient).block_hash(BlockId::Number(1)).unwrap();
+
+		let env = TestEnv::new();
+		*env.chain.queue_full_above.write() = Some(4);
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![first]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 10).unwrap();
+
+		let outcome = downloader.collect_blocks_detailed(&mut io, false, &mut |_, _| {});
+		let expected: Vec<_> = (5..11)
+			.map(|n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap())
+			.collect();
+		assert_eq!(outcome.result, Ok(()));
+		assert_eq!(outcome.abandoned, expected);
+		assert_eq!(downloader.last_imported_block_number(), 4);
+	}
+
+	#[test]
+	fn skips_headers_already_in_chain() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(300, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		env.chain.add_blocks(100, EachBlockWith::Nothing);
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		let last_known = (&env.chain as &BlockChainClient).block_hash(BlockId::Number(100)).unwrap();
+		match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Headers { start, skip: 0, .. }) => assert_eq!(start, last_known),
+			_ => panic!("expected a headers request"),
+		}
+
+		// Downloading continues from the first known block, which is imported as already in chain.
+		import_headers(&mut downloader, &mut io, &remote, 100, 128).unwrap();
+		downloader.collect_blocks(&mut io, false).unwrap();
+		assert_eq!(downloader.last_imported_block_number(), 227);
+	}
+
+	#[test]
+	fn reset_keeps_round_parents() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let first = (&remote as &BlockChainClient).block_hash(BlockId::Number(1)).unwrap();
+		let ninth = (&remote as &BlockChainClient).block_hash(BlockId::Number(9)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![first]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 10).unwrap();
+		downloader.collect_blocks(&mut io, false).unwrap();
+		assert_eq!(downloader.last_imported_block_number(), 10);
+
+		// Start a new round, abandon it and import nothing.
+		downloader.request_blocks(&mut io, 0);
+		downloader.reset(ResetReason::Requested);
+		downloader.collect_blocks(&mut io, false).unwrap();
+
+		// A chain without these blocks can't be probed, so the parent must come from the last round.
+		let empty = TestEnv::new();
+		let mut io = empty.io();
+		match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Headers { start, .. }) => assert_eq!(start, ninth),
+			_ => panic!("expected a headers request"),
+		}
+		assert_eq!(downloader.last_imported_block_number(), 9);
+	}
+
+	#[test]
+	fn rejects_receipts_not_matching_receipts_root() {
+		fn receipts_rlp(data: &str) -> Vec<u8> {
+			let mut stream = RlpStream::new_list(1);
+			stream.append(&data);
+			stream.out()
+		}
+
+		let receipts = receipts_rlp("receipt");
+		let mut parent = BlockHeader::new();
+		parent.set_number(0);
+		let mut header = BlockHeader::new();
+		header.set_number(1);
+		header.set_parent_hash(parent.hash());
+		header.set_receipts_root(ordered_trie_root(Rlp::new(&receipts).iter().map(|r| r.as_raw())));
+
+		let mut stream = RlpStream::new_list(2);
+		stream.append(&parent);
+		stream.append(&header);
+		let headers = stream.out();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::with_unlimited_reorg(true, &parent.hash(), 0);
+		downloader.reset_to(vec![parent.hash()]);
+		downloader.import_headers(&mut io, &Rlp::new(&headers), Some(parent.hash())).unwrap();
+
+		let pack = |block_receipts: &[u8]| {
+			let mut stream = RlpStream::new_list(1);
+			stream.append_raw(block_receipts, 1);
+			stream.out()
+		};
+		let tampered = pack(&receipts_rlp("tampered"));
+		assert_eq!(downloader.import_receipts(&mut io, &Rlp::new(&tampered), &[header.hash()]), Err(BlockDownloaderImportError::Invalid(InvalidReason::ReceiptsMismatch)));
+		assert_eq!(downloader.import_receipts(&mut io, &Rlp::new(&pack(&receipts)), &[header.hash()]), Ok(()));
+	}
+
+	#[test]
+	fn completes_at_max_block() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(20, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let first = (&remote as &BlockChainClient).block_hash(BlockId::Number(1)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.set_max_block(Some(10));
+		downloader.reset_to(vec![first]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 20).unwrap();
+		assert_eq!(downloader.highest_block, Some(10));
+
+		downloader.collect_blocks(&mut io, false).unwrap();
+		assert_eq!(downloader.last_imported_block_number(), 10);
+		assert!(downloader.is_complete());
+	}
+
+	#[test]
+	fn max_block_not_reached() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(5, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let first = (&remote as &BlockChainClient).block_hash(BlockId::Number(1)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.set_max_block(Some(10));
+		downloader.reset_to(vec![first]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 5).unwrap();
+
+		downloader.collect_blocks(&mut io, false).unwrap();
+		assert_eq!(downloader.last_imported_block_number(), 5);
+		assert!(!downloader.is_complete());
+	}
+
+	#[test]
+	fn reports_response_quality() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		// Headers arriving while idle are not needed.
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		assert_eq!(import_headers(&mut downloader, &mut io, &remote, 0, 10), Ok((DownloadAction::None, ResponseQuality::Redundant)));
+		assert_eq!(downloader.import_bodies(&Rlp::new(&bodies_rlp(&remote, &[1]))), Ok(ResponseQuality::Redundant));
+
+		// No subchain heads past the common block.
+		downloader.request_blocks(&mut io, 0);
+		let bytes = RlpStream::new_list(0).out();
+		assert_eq!(downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(genesis)), Ok((DownloadAction::None, ResponseQuality::Useless)));
+
+		downloader.reset_to(vec![genesis]);
+		assert_eq!(import_headers(&mut downloader, &mut io, &remote, 0, 10), Ok((DownloadAction::None, ResponseQuality::Useful)));
+		assert_eq!(import_headers(&mut downloader, &mut io, &remote, 0, 10), Err(BlockDownloaderImportError::Useless));
+		assert_eq!(downloader.import_bodies(&Rlp::new(&bodies_rlp(&remote, &[1]))), Ok(ResponseQuality::Useful));
+		assert_eq!(downloader.import_bodies(&Rlp::new(&RlpStream::new_list(0).out())), Err(BlockDownloaderImportError::Useless));
+	}
+
+	#[test]
+	fn known_headers_response_is_not_decoded() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(200, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let first = (&remote as &BlockChainClient).block_hash(BlockId::Number(1)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![first]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 128).unwrap();
+		assert_eq!(import_headers(&mut downloader, &mut io, &remote, 1, 128), Err(BlockDownloaderImportError::Useless));
+
+		// Only the first and last headers are decoded, the malformed middle is never looked at.
+		let mut stream = RlpStream::new_list(3);
+		stream.append_raw(&(&remote as &BlockChainClient).block_header(BlockId::Number(1)).unwrap().into_inner(), 1);
+		stream.append(&"not a header");
+		stream.append_raw(&(&remote as &BlockChainClient).block_header(BlockId::Number(128)).unwrap().into_inner(), 1);
+		let bytes = stream.out();
+		assert_eq!(downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(first)), Err(BlockDownloaderImportError::Useless));
+		assert_eq!(downloader.useless_headers_count, 2);
+	}
+
+	#[test]
+	fn headers_extending_a_known_subchain_are_imported() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(3, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let hash = |n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		// A single block subchain at the tip, then a response starting with it and carrying new blocks.
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![hash(1)]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 1).unwrap();
+		assert_eq!(import_headers(&mut downloader, &mut io, &remote, 1, 3), Ok((DownloadAction::None, ResponseQuality::Useful)));
+		assert!(downloader.blocks.contains(&hash(2)));
+		assert!(downloader.blocks.contains(&hash(3)));
+		assert_eq!(downloader.useless_headers_count, 0);
+	}
+
+	#[test]
+	fn new_blocks_request_bodies_from_tip() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(100, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+		let hashes = |numbers: &mut Iterator<Item = BlockNumber>| -> Vec<H256> {
+			numbers.map(|n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap()).collect()
+		};
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::for_new_blocks(&genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.request_blocks(&mut io, 0);
+		import_headers(&mut downloader, &mut io, &remote, 0, 100).unwrap();
+		let tip = 99;
+		let expected = hashes(&mut (tip + 1 - MAX_BODIES_TO_REQUEST as BlockNumber..tip + 1).rev());
+		assert_eq!(downloader.plan_requests(1), vec![BlockRequest::Bodies { hashes: expected.clone() }]);
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::Bodies { hashes: expected }));
+
+		// Old blocks are still downloaded in chain order.
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.request_blocks(&mut io, 0);
+		import_headers(&mut downloader, &mut io, &remote, 0, 100).unwrap();
+		let expected = hashes(&mut (1..MAX_BODIES_TO_REQUEST as BlockNumber + 1));
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::Bodies { hashes: expected }));
+
+		// So are new blocks downloaded in several subchains, far from the chain head.
+		let mut downloader = BlockDownloader::for_new_blocks(&genesis, 0);
+		let middle = hashes(&mut (50..51))[0];
+		downloader.reset_to(vec![genesis, middle]);
+		import_headers(&mut downloader, &mut io, &remote, 0, 40).unwrap();
+		import_headers(&mut downloader, &mut io, &remote, 50, 50).unwrap();
+		assert_eq!(downloader.subchain_heads().len(), 2);
+		let expected = hashes(&mut (1..MAX_BODIES_TO_REQUEST as BlockNumber + 1));
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::Bodies { hashes: expected }));
+	}
+
+	#[test]
+	fn counts_received_bytes() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.request_blocks(&mut io, 0);
+		assert_eq!(downloader.transfer_stats(), TransferStats::default());
+
+		let headers_bytes: u64 = (0..10)
+			.map(|n| (&remote as &BlockChainClient).block_header(BlockId::Number(n)).unwrap().into_inner().len() as u64)
+			.sum();
+		import_headers(&mut downloader, &mut io, &remote, 0, 10).unwrap();
+
+		let bodies_bytes: u64 = (1..10)
+			.map(|n| (&remote as &BlockChainClient).block_body(BlockId::Number(n)).unwrap().into_inner().len() as u64)
+			.sum();
+		downloader.import_bodies(&Rlp::new(&bodies_rlp(&remote, &(1..10).collect::<Vec<_>>()))).unwrap();
+
+		// Receipts are not expected by this downloader, but they are still accounted for.
+		let mut stream = RlpStream::new_list(2);
+		stream.append_raw(&[0xc0], 1);
+		stream.append_raw(&[0xc0], 1);
+		let _ = downloader.import_receipts(&mut io, &Rlp::new(&stream.out()), &[]);
+
+		assert_eq!(downloader.transfer_stats(), TransferStats {
+			headers_bytes,
+			bodies_bytes,
+			receipts_bytes: 2,
+		});
+	}
+
+	#[test]
+	fn empty_bodies_are_benign_at_chain_head() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+		let empty = RlpStream::new_list(0).out();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.request_blocks(&mut io, 0);
+		import_headers(&mut downloader, &mut io, &remote, 0, 10).unwrap();
+		assert!(downloader.request_blocks(&mut io, 0).is_some());
+		assert_eq!(downloader.import_bodies(&Rlp::new(&empty)), Ok(ResponseQuality::Useless));
+	}
+
+	#[test]
+	fn empty_bodies_are_useless_mid_sync() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(300, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+		let empty = RlpStream::new_list(0).out();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		let skip = match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Headers { skip, .. }) => skip,
+			_ => panic!("expected a headers request"),
+		};
+		let bytes = headers_rlp_at(&remote, &[0, skip + 1, 2 * (skip + 1)]);
+		downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(genesis)).unwrap();
+		assert_eq!(downloader.state, State::Blocks);
+		assert_eq!(downloader.import_bodies(&Rlp::new(&empty)), Err(BlockDownloaderImportError::Useless));
+	}
+
+	#[test]
+	fn stalls_after_rounds_without_progress() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(1, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.request_blocks(&mut io, 0);
+		for round in 0..3 {
+			assert!(!downloader.is_stalled(3), "stalled after {} rounds", round);
+			downloader.collect_blocks(&mut io, false).unwrap();
+			assert_eq!(downloader.state, State::Idle);
+			downloader.request_blocks(&mut io, 0);
+		}
+		assert!(downloader.is_stalled(3));
+
+		let block = Unverified::from_rlp((&remote as &BlockChainClient).block(BlockId::Number(1)).unwrap().into_inner()).unwrap();
+		downloader.import_blocks(&mut io, vec![BlockAndReceipts { block, receipts: None }], false, &mut |_, _| {});
+		assert!(!downloader.is_stalled(1));
+	}
+
+	#[test]
+	fn rejects_blacklisted_headers() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(20, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let bad = (&remote as &BlockChainClient).block_hash(BlockId::Number(15)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.request_blocks(&mut io, 0);
+		import_headers(&mut downloader, &mut io, &remote, 0, 10).unwrap();
+
+		downloader.blacklist(&[bad]);
+		assert_eq!(
+			import_headers(&mut downloader, &mut io, &remote, 9, 11).err(),
+			Some(BlockDownloaderImportError::Invalid(InvalidReason::KnownBadBlock))
+		);
+		assert!(!downloader.blocks.contains(&bad));
+	}
+
+	#[test]
+	fn rejects_headers_with_broken_parent_link() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(20, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let header = |n| -> BlockHeader {
+			::rlp::decode(&(&remote as &BlockChainClient).block_header(BlockId::Number(n)).unwrap().into_inner()).unwrap()
+		};
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.request_blocks(&mut io, 0);
+		import_headers(&mut downloader, &mut io, &remote, 0, 10).unwrap();
+
+		let mut broken = header(12);
+		broken.set_parent_hash(H256::from(1));
+		let mut stream = RlpStream::new_list(3);
+		stream.append(&header(10));
+		stream.append(&header(11));
+		stream.append(&broken);
+		let bytes = stream.out();
+
+		assert_eq!(
+			downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(header(10).hash())).err(),
+			Some(BlockDownloaderImportError::Invalid(InvalidReason::BrokenChain))
+		);
+		assert!(!downloader.blocks.contains(&header(10).hash()));
+		assert_eq!(import_headers(&mut downloader, &mut io, &remote, 10, 3).map(|(_, quality)| quality), Ok(ResponseQuality::Useful));
+	}
+
+	#[test]
+	fn peek_ready_lists_blocks_to_be_drained() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(20, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.request_blocks(&mut io, 0);
+		import_headers(&mut downloader, &mut io, &remote, 0, 20).unwrap();
+		// The genesis block has an empty body and is ready right away.
+		assert_eq!(downloader.peek_ready().count(), 1);
+
+		// Bodies of blocks 1 to 5 and 8 to 10: only the first run is ready.
+		let numbers = [1, 2, 3, 4, 5, 8, 9, 10];
+		downloader.import_bodies(&Rlp::new(&bodies_rlp(&remote, &numbers))).unwrap();
+		let peeked: Vec<(H256, BlockNumber)> = downloader.peek_ready().map(|(h, n)| (*h, n)).collect();
+		assert_eq!(peeked.iter().map(|&(_, n)| n).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4, 5]);
+		assert_eq!(downloader.peek_ready().count(), peeked.len());
+
+		let drained: Vec<(H256, BlockNumber)> = downloader.blocks.drain().into_iter()
+			.map(|b| (b.block.header.hash(), b.block.header.number()))
+			.collect();
+		assert_eq!(peeked, drained);
+	}
+
+	#[test]
+	fn assigns_subchains_to_distinct_idle_peers() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(600, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		let peers = [1, 2, 3];
+		let mut inflight = HashMap::new();
+		assert!(downloader.assign_work(&peers, &inflight).is_empty());
+
+		let skip = match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Headers { skip, .. }) => skip,
+			_ => panic!("expected a headers request"),
+		};
+		let heads: Vec<BlockNumber> = (0..4).map(|i| i * (skip + 1)).collect();
+		downloader.import_headers(&mut io, &Rlp::new(&headers_rlp_at(&remote, &heads)), Some(genesis)).unwrap();
+		assert_eq!(downloader.state, State::Blocks);
+
+		// Peer 2 is busy, the other two get a subchain each.
+		inflight.insert(2, 1);
+		let assigned = downloader.assign_work(&peers, &inflight);
+		let starts: Vec<(PeerId, H256)> = assigned.into_iter().map(|(peer, request)| match request {
+			BlockRequest::Headers { start, skip: 0, .. } => (peer, start),
+			_ => panic!("expected a subchain headers request"),
+		}).collect();
+		let head_hash = |n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+		assert_eq!(starts, vec![(1, head_hash(heads[0])), (3, head_hash(heads[1]))]);
+
+		// Once peer 2 is idle it gets the next subchain rather than one already assigned.
+		inflight.clear();
+		inflight.insert(1, 1);
+		inflight.insert(3, 1);
+		let assigned = downloader.assign_work(&peers, &inflight);
+		assert_eq!(assigned.len(), 1);
+		match assigned[0] {
+			(2, BlockRequest::Headers { start, .. }) => assert_eq!(start, head_hash(heads[2])),
+			_ => panic!("expected a subchain headers request for peer 2"),
+		}
+
+		// The parallel download limit counts busy peers.
+		let config = BlockDownloaderConfig { max_parallel_subchain_download: 2, ..Default::default() };
+		downloader.config = config;
+		inflight.clear();
+		inflight.insert(2, 1);
+		assert_eq!(downloader.assign_work(&peers, &inflight).len(), 1);
+	}
+
+	#[test]
+	fn memory_limit_pauses_header_requests() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(100, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		for &(memory_limit, expect_headers) in &[(usize::max_value(), true), (1, false)] {
+			let config = BlockDownloaderConfig { memory_limit, ..Default::default() };
+			let mut downloader = BlockDownloader::new_with_config(false, &genesis, 0, config);
+			downloader.reset_to(vec![genesis]);
+			downloader.request_blocks(&mut io, 0);
+			import_headers(&mut downloader, &mut io, &remote, 0, 100).unwrap();
+			assert!(downloader.heap_size() > 1);
+
+			let mut requests = Vec::new();
+			while let Some(request) = downloader.request_blocks(&mut io, 0) {
+				let is_headers = match request {
+					BlockRequest::Headers { .. } => true,
+					_ => false,
+				};
+				requests.push(request);
+				if is_headers {
+					break;
+				}
+			}
+			assert!(requests.len() > 1);
+			assert!(match requests[0] { BlockRequest::Bodies { .. } => true, _ => false });
+			assert_eq!(match requests[requests.len() - 1] { BlockRequest::Headers { .. } => true, _ => false }, expect_headers);
+		}
+	}
+
+	#[test]
+	fn reports_reorg_depth_after_retracting() {
+		let env = TestEnv::new();
+		env.chain.add_blocks(10, EachBlockWith::Nothing);
+		let block_hash = |n| (&env.chain as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &block_hash(10), 10);
+		downloader.request_blocks(&mut io, 0);
+		// Rounds that import nothing step back 1, 2 and 4 blocks.
+		for _ in 0..3 {
+			downloader.collect_blocks(&mut io, false).unwrap();
+			downloader.request_blocks(&mut io, 0);
+		}
+		assert_eq!(downloader.last_imported_block_number(), 3);
+		assert_eq!(downloader.last_reorg_depth(), None);
+
+		let bytes = headers_rlp_at(&env.chain, &[3]);
+		downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(block_hash(3))).unwrap();
+		assert_eq!(downloader.last_reorg_depth(), Some(7));
+	}
+
+	/// Headers of a three block chain whose blocks have distinct non-empty bodies and receipts,
+	/// along with the bodies and receipts of the last two blocks.
+	fn blocks_with_receipts() -> (Vec<BlockHeader>, Vec<Vec<u8>>, Vec<Vec<u8>>) {
+		let mut headers = Vec::new();
+		let mut bodies = Vec::new();
+		let mut receipts = Vec::new();
+		let mut parent = BlockHeader::new();
+		parent.set_number(0);
+		headers.push(parent.clone());
+		for n in 1..3 {
+			let mut uncle = BlockHeader::new();
+			uncle.set_number(100 + n);
+			let mut uncles = RlpStream::new_list(1);
+			uncles.append(&uncle);
+			let uncles = uncles.out();
+			let mut body = RlpStream::new_list(2);
+			body.begin_list(0);
+			body.append_raw(&uncles, 1);
+
+			let mut block_receipts = RlpStream::new_list(1);
+			block_receipts.append(&format!("receipt {}", n));
+			let block_receipts = block_receipts.out();
+
+			let mut header = BlockHeader::new();
+			header.set_number(n);
+			header.set_parent_hash(parent.hash());
+			header.set_uncles_hash(::hash::keccak(&uncles));
+			header.set_receipts_root(ordered_trie_root(Rlp::new(&block_receipts).iter().map(|r| r.as_raw())));
+			parent = header.clone();
+			headers.push(header);
+			bodies.push(body.out());
+			receipts.push(block_receipts);
+		}
+		(headers, bodies, receipts)
+	}
+
+	#[test]
+	fn receipts_count_checked_against_request() {
+		let (headers, _, receipts) = blocks_with_receipts();
+		let genesis = headers[0].hash();
+		let mut stream = RlpStream::new_list(headers.len());
+		for header in &headers {
+			stream.append(header);
+		}
+		let headers_rlp = stream.out();
+		let requested = [headers[1].hash(), headers[2].hash()];
+		let pack = |receipts: &[Vec<u8>]| {
+			let mut stream = RlpStream::new_list(receipts.len());
+			for block_receipts in receipts {
+				stream.append_raw(block_receipts, 1);
+			}
+			stream.out()
+		};
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(true, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.import_headers(&mut io, &Rlp::new(&headers_rlp), Some(genesis)).unwrap();
+
+		// Over-delivery.
+		assert_eq!(downloader.import_receipts(&mut io, &Rlp::new(&pack(&receipts)), &requested[..1]), Err(BlockDownloaderImportError::Invalid(InvalidReason::TooManyReceipts)));
+
+		// Under-delivery is partial. As in the handler, the peer's download is cleared first, so
+		// the missing receipts can be requested again.
+		downloader.blocks.mark_receipt_download(&requested);
+		downloader.clear_receipt_download(&requested);
+		assert_eq!(downloader.import_receipts(&mut io, &Rlp::new(&pack(&receipts[..1])), &requested), Ok(()));
+		let pending: Vec<_> = downloader.blocks.pending_receipts(usize::max_value(), &HashSet::new()).into_iter().map(|(h, _)| h).collect();
+		assert_eq!(pending, vec![headers[2].hash()]);
+
+		assert_eq!(downloader.import_receipts(&mut io, &Rlp::new(&pack(&receipts[1..])), &requested[1..]), Ok(()));
+		assert!(downloader.blocks.pending_receipts(usize::max_value(), &HashSet::new()).is_empty());
+	}
+
+	#[test]
+	fn lists_subchain_heads() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let heads: Vec<_> = [1, 5, 9].iter()
+			.map(|&n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap())
+			.collect();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		assert!(downloader.subchain_heads().is_empty());
+
+		downloader.reset_to(heads.clone());
+		assert_eq!(downloader.subchain_heads(), heads);
+	}
+
+	#[test]
+	fn useless_headers_threshold_depends_on_distance_to_tip() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let heads: Vec<_> = [1, 5, 9].iter()
+			.map(|&n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap())
+			.collect();
+
+		let downloader_with = |highest_block| {
+			let mut downloader = BlockDownloader::new(false, &genesis, 0);
+			downloader.reset_to(heads.clone());
+			downloader.highest_block = highest_block;
+			downloader
+		};
+
+		// Near the tip more useless responses are tolerated.
+		let mut downloader = downloader_with(Some(100));
+		assert_eq!(downloader.useless_threshold(), MAX_USELESS_HEADERS_PER_ROUND);
+		for _ in 1..MAX_USELESS_HEADERS_PER_ROUND {
+			assert_eq!(downloader.useless_headers(), Err(BlockDownloaderImportError::Useless));
+			assert_eq!(downloader.state, State::Blocks);
+		}
+		assert_eq!(downloader.useless_headers(), Err(BlockDownloaderImportError::Useless));
+		assert_eq!(downloader.state, State::Idle);
+		assert_eq!(downloader.last_reset_reason(), Some(ResetReason::UselessHeaders));
+
+		// Mid-sync the downloader gives up on the round sooner.
+		let mut downloader = downloader_with(Some(100_000));
+		assert_eq!(downloader.useless_threshold(), MIN_USELESS_HEADERS_PER_ROUND);
+		for _ in 1..MIN_USELESS_HEADERS_PER_ROUND {
+			assert_eq!(downloader.useless_headers(), Err(BlockDownloaderImportError::Useless));
+			assert_eq!(downloader.state, State::Blocks);
+		}
+		assert_eq!(downloader.useless_headers(), Err(BlockDownloaderImportError::Useless));
+		assert_eq!(downloader.state, State::Idle);
+
+		// A single subchain is never reset for useless headers.
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![heads[0]]);
+		downloader.highest_block = Some(100_000);
+		for _ in 0..MAX_USELESS_HEADERS_PER_ROUND {
+			assert_eq!(downloader.useless_headers(), Err(BlockDownloaderImportError::Useless));
+		}
+		assert_eq!(downloader.state, State::Blocks);
+	}
+
+	#[test]
+	fn pause_keeps_downloaded_blocks() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.request_blocks(&mut io, 0);
+		import_headers(&mut downloader, &mut io, &remote, 0, 10).unwrap();
+		assert_eq!(downloader.state, State::Blocks);
+		let queued = downloader.status().queued_blocks;
+
+		downloader.pause();
+		assert_eq!(downloader.state, State::Paused);
+		assert!(downloader.request_blocks(&mut io, 0).is_none());
+		assert!(downloader.plan_requests(10).is_empty());
+		downloader.collect_blocks(&mut io, false).unwrap();
+		assert_eq!(downloader.status().queued_blocks, queued);
+
+		downloader.resume();
+		assert_eq!(downloader.state, State::Blocks);
+		match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Bodies { .. }) => (),
+			_ => panic!("expected a bodies request"),
+		}
+	}
+
+	#[test]
+	fn reset_keeps_download_paused() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		import_headers(&mut downloader, &mut io, &remote, 0, 10).unwrap();
+		downloader.pause();
+
+		downloader.reset(ResetReason::Requested);
+		assert_eq!(downloader.state, State::Paused);
+		assert_eq!(downloader.status().queued_blocks, 0);
+		assert!(downloader.request_blocks(&mut io, 0).is_none());
+		assert_eq!(downloader.check_invariants(), Ok(()));
+
+		downloader.reset_to(vec![genesis]);
+		assert_eq!(downloader.state, State::Paused);
+		assert!(downloader.request_blocks(&mut io, 0).is_none());
+
+		downloader.resume();
+		assert_eq!(downloader.state, State::Blocks);
+		match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Headers { .. }) => (),
+			_ => panic!("expected a headers request"),
+		}
+	}
+
+	#[test]
+	fn reports_highest_block() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(20, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		assert_eq!(downloader.highest_block(), None);
+
+		downloader.reset_to(vec![genesis]);
+		import_headers(&mut downloader, &mut io, &remote, 0, 10).unwrap();
+		assert_eq!(downloader.highest_block(), Some(9));
+
+		downloader.set_max_block(Some(15));
+		assert_eq!(downloader.highest_block(), Some(15));
+		// Headers above the max block don't raise it.
+		let _ = import_headers(&mut downloader, &mut io, &remote, 10, 10);
+		assert_eq!(downloader.highest_block(), Some(15));
+	}
+
+	#[test]
+	fn scales_subchain_heads_request_to_short_chains() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let heads_request = |highest_block| {
+			let mut io = env.io();
+			let mut downloader = BlockDownloader::new(false, &genesis, 0);
+			downloader.highest_block = highest_block;
+			match downloader.request_blocks(&mut io, 0) {
+				Some(BlockRequest::Headers { count, skip, .. }) => (count, skip),
+				_ => panic!("expected a headers request"),
+			}
+		};
+
+		// Nothing is known about the chain length.
+		assert_eq!(heads_request(None), (SUBCHAIN_SIZE, MAX_HEADERS_TO_REQUEST as u64 - 2));
+		// Heads stay a full header request apart, only their number is scaled to the chain length.
+		let skip = MAX_HEADERS_TO_REQUEST as u64 - 2;
+		assert_eq!(heads_request(Some(10)), (MIN_SUBCHAIN_SIZE, skip));
+		assert_eq!(heads_request(Some(2)), (MIN_SUBCHAIN_SIZE, skip));
+		assert_eq!(heads_request(Some(1000)), (MIN_SUBCHAIN_SIZE, skip));
+		assert_eq!(heads_request(Some(5000)), (40, skip));
+		assert_eq!(heads_request(Some(1_000_000)), (SUBCHAIN_SIZE, skip));
+
+		// A chain shorter than a header request is downloaded as a single subchain.
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.highest_block = Some(10);
+		downloader.request_blocks(&mut io, 0);
+		let bytes = headers_rlp_at(&remote, &[0]);
+		assert_eq!(downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(genesis)), Ok((DownloadAction::Reset, ResponseQuality::Useful)));
+		assert_eq!(downloader.subchain_heads().len(), 1);
+		match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Headers { start, count, skip: 0, .. }) => {
+				assert_eq!(start, genesis);
+				assert_eq!(count, MAX_HEADERS_TO_REQUEST as u64);
+			},
+			_ => panic!("expected a subchain headers request"),
+		}
+	}
+
+	#[test]
+	fn breaks_down_headers_response() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(20, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+
+		// The first blocks are already in the local chain.
+		let env = TestEnv::new();
+		env.chain.add_blocks(3, EachBlockWith::Nothing);
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		import_headers(&mut downloader, &mut io, &remote, 0, 10).unwrap();
+		assert_eq!(downloader.last_response_breakdown(), HeadersBreakdown {
+			new: 6,
+			in_chain: 4,
+			..Default::default()
+		});
+
+		downloader.set_max_block(Some(12));
+		import_headers(&mut downloader, &mut io, &remote, 5, 10).unwrap();
+		assert_eq!(downloader.last_response_breakdown(), HeadersBreakdown {
+			new: 3,
+			already_contained: 5,
+			above_max_block: 2,
+			..Default::default()
+		});
+	}
+
+	#[test]
+	fn imports_subchains_downloaded_out_of_order_contiguously() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(20, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let hash = |n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![hash(1), hash(6), hash(11)]);
+		import_headers(&mut downloader, &mut io, &remote, 11, 5).unwrap();
+		import_headers(&mut downloader, &mut io, &remote, 6, 5).unwrap();
+		assert_eq!(downloader.peek_ready().count(), 0);
+		import_headers(&mut downloader, &mut io, &remote, 1, 5).unwrap();
+
+		let mut imported = Vec::new();
+		let outcome = downloader.collect_blocks_detailed(&mut io, false, &mut |_, number| imported.push(number));
+		assert_eq!(outcome.result, Ok(()));
+		assert!(outcome.abandoned.is_empty());
+		assert_eq!(imported, (1..16).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn fills_gap_below_subchains_by_number() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(100, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let hash = |n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis, hash(50)]);
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::Headers { start: genesis, count: 128, skip: 0, subchain: Some(genesis) }));
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::Headers { start: hash(50), count: 128, skip: 0, subchain: Some(hash(50)) }));
+		import_headers(&mut downloader, &mut io, &remote, 50, 10).unwrap();
+		// Nothing leads to blocks 1 to 49 any more.
+		assert!(downloader.cancel_subchain(&genesis));
+
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::Headers { start: hash(59), count: 128, skip: 0, subchain: Some(hash(59)) }));
+		assert_eq!(downloader.plan_requests(1), vec![BlockRequest::HeadersByNumber { start_number: 1, count: 49 }]);
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::HeadersByNumber { start_number: 1, count: 49 }));
+		assert_eq!(downloader.request_blocks(&mut io, 0), None, "the range is only requested once");
+
+		assert_eq!(downloader.header_range_request(), Some((1, 49)));
+		let bytes = headers_rlp(&remote, 1, 49);
+		assert_eq!(downloader.import_header_range(&mut io, &Rlp::new(&bytes), 1, 49).unwrap(), (DownloadAction::None, ResponseQuality::Useful));
+		assert_eq!(downloader.header_range_request(), None);
+
+		downloader.collect_blocks(&mut io, false).unwrap();
+		assert_eq!(downloader.last_imported_block_number(), 59);
+		// A late response for a range that was imported meanwhile.
+		assert_eq!(downloader.import_header_range(&mut io, &Rlp::new(&bytes), 1, 49).unwrap(), (DownloadAction::None, ResponseQuality::Redundant));
+	}
+
+	#[test]
+	fn rejects_header_range_not_following_last_imported_block() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(100, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let hash = |n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis, hash(50)]);
+		downloader.request_blocks(&mut io, 0);
+		downloader.request_blocks(&mut io, 0);
+		import_headers(&mut downloader, &mut io, &remote, 50, 10).unwrap();
+		downloader.cancel_subchain(&genesis);
+		downloader.request_blocks(&mut io, 0);
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::HeadersByNumber { start_number: 1, count: 49 }));
+
+		let bytes = headers_rlp(&remote, 2, 10);
+		assert_eq!(downloader.import_header_range(&mut io, &Rlp::new(&bytes), 1, 49), Err(BlockDownloaderImportError::Invalid(InvalidReason::BrokenChain)));
+		// The range can be requested from another peer.
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::HeadersByNumber { start_number: 1, count: 49 }));
+	}
+
+	#[test]
+	fn keeps_lowest_subchain_heads_up_to_limit() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(1100, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let hash = |n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let config = BlockDownloaderConfig { max_heads: 2, ..Default::default() };
+		let mut downloader = BlockDownloader::new_with_config(false, &genesis, 0, config);
+		let step = match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Headers { skip, .. }) => skip + 1,
+			_ => panic!("expected a headers request"),
+		};
+
+		let bytes = headers_rlp_at(&remote, &[0, step, 2 * step, 3 * step]);
+		downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(genesis)).unwrap();
+		assert_eq!(downloader.subchain_heads(), vec![genesis, hash(step)]);
+	}
+
+	#[test]
+	fn knows_numbers_of_downloaded_headers() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(100, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let hash = |n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis, hash(50)]);
+		downloader.request_blocks(&mut io, 0);
+		downloader.request_blocks(&mut io, 0);
+		import_headers(&mut downloader, &mut io, &remote, 50, 10).unwrap();
+
+		assert_eq!(downloader.known_header_number(&hash(50)), Some(50));
+		assert_eq!(downloader.known_header_number(&hash(59)), Some(59));
+		assert_eq!(downloader.known_header_number(&hash(60)), None);
+		assert_eq!(downloader.known_header_number(&hash(10)), None);
+	}
+
+	#[test]
+	fn collect_blocks_reports_import_count_and_duration() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let first = (&remote as &BlockChainClient).block_hash(BlockId::Number(1)).unwrap();
+
+		let env = TestEnv::new();
+		*env.chain.queue_full_above.write() = Some(4);
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![first]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 10).unwrap();
+
+		let started = Instant::now();
+		let mut queued = 0;
+		let outcome = downloader.collect_blocks_detailed(&mut io, false, &mut |_, _| queued += 1);
+		// Blocks above 4 don't fit in the queue.
+		assert_eq!(queued, 4);
+		assert_eq!(outcome.imported, 4);
+		// The import is timed, not left at the default.
+		assert!(outcome.elapsed > Duration::from_secs(0));
+		assert!(outcome.elapsed <= started.elapsed());
+	}
+
+	#[test]
+	fn requeued_receipts_are_requested_again() {
+		let (headers, bodies, _) = blocks_with_receipts();
+		let genesis = headers[0].hash();
+		let mut stream = RlpStream::new_list(headers.len());
+		for header in &headers {
+			stream.append(header);
+		}
+		let headers_rlp = stream.out();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(true, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.import_headers(&mut io, &Rlp::new(&headers_rlp), Some(genesis)).unwrap();
+
+		let hashes = vec![headers[1].hash(), headers[2].hash()];
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::Bodies { hashes: hashes.clone() }));
+		let mut response = RlpStream::new_list(bodies.len());
+		for body in &bodies {
+			response.append_raw(body, 1);
+		}
+		downloader.import_bodies(&Rlp::new(&response.out())).unwrap();
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::Receipts { hashes: hashes.clone() }));
+
+		// The receipts response never arrives.
+		assert!(downloader.blocks.pending_receipts(usize::max_value(), &HashSet::new()).is_empty());
+		downloader.requeue_receipts(&hashes);
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::Receipts { hashes }));
+	}
+
+	#[test]
+	fn reorders_single_swapped_header_pair() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		let bytes = headers_rlp_at(&remote, &[0, 1, 3, 2, 4, 5]);
+		assert_eq!(downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(genesis)), Ok((DownloadAction::None, ResponseQuality::Useful)));
+		assert_eq!(downloader.status().queued_blocks, 6);
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		let bytes = headers_rlp_at(&remote, &[0, 2, 1, 4, 3, 5]);
+		assert_eq!(downloader.import_headers(&mut io, &Rlp::new(&bytes), Some(genesis)), Err(BlockDownloaderImportError::Invalid(InvalidReason::BrokenChain)));
+	}
+
+	#[test]
+	fn rejects_headers_conflicting_with_required_block() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let fork = TestBlockChainClient::new();
+		fork.add_blocks(10, EachBlockWith::Uncle);
+		let genesis = remote.genesis_hash;
+		let required = (&remote as &BlockChainClient).block_hash(BlockId::Number(3)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.require_block(3, required);
+		downloader.reset_to(vec![genesis]);
+		assert_eq!(
+			import_headers(&mut downloader, &mut io, &fork, 0, 5),
+			Err(BlockDownloaderImportError::Invalid(InvalidReason::WrongFork))
+		);
+		assert_eq!(import_headers(&mut downloader, &mut io, &remote, 0, 5), Ok((DownloadAction::None, ResponseQuality::Useful)));
+		assert_eq!(downloader.known_header_number(&required), Some(3));
+	}
+
+	#[test]
+	fn records_import_latency() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let first = (&remote as &BlockChainClient).block_hash(BlockId::Number(1)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![first]);
+		import_headers(&mut downloader, &mut io, &remote, 1, 3).unwrap();
+		downloader.collect_blocks(&mut io, false).unwrap();
+
+		assert_eq!(downloader.last_imported_block_number(), 3);
+		// Every import call is recorded.
+		assert_eq!(downloader.import_latency_histogram().iter().sum::<u64>(), 3);
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		for &micros in &[500, 999, 5_000, 50_000, 500_000, 100_000, 2_000_000] {
+			downloader.record_import_latency(Duration::from_micros(micros));
+		}
+		assert_eq!(downloader.import_latency_histogram(), [2, 1, 1, 3]);
+	}
+
+	#[test]
+	fn estimates_bytes_remaining_from_average_block_size() {
+		let genesis = H256::new();
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		assert_eq!(downloader.estimated_bytes_remaining(), None);
+
+		downloader.transfer_stats = TransferStats { headers_bytes: 500, bodies_bytes: 4_500, receipts_bytes: 0 };
+		downloader.blocks_imported = 10;
+		downloader.last_imported_block = 10;
+		assert_eq!(downloader.estimated_bytes_remaining(), None, "highest block is not known");
+
+		downloader.highest_block = Some(110);
+		assert_eq!(downloader.estimated_bytes_remaining(), Some(100 * 500));
+	}
+
+	#[test]
+	fn rejects_bodies_mixed_with_garbage_without_inserting_any() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.request_blocks(&mut io, 0);
+		import_headers(&mut downloader, &mut io, &remote, 0, 10).unwrap();
+
+		let valid = bodies_rlp(&remote, &[1, 2]);
+		let valid = Rlp::new(&valid);
+		let mut stream = RlpStream::new_list(3);
+		stream.append_raw(valid.at(0).unwrap().as_raw(), 1);
+		stream.begin_list(2).begin_list(0);
+		stream.begin_list(0);
+		stream.append_raw(valid.at(1).unwrap().as_raw(), 1);
+		let mixed = stream.out();
+
+		assert_eq!(downloader.import_bodies(&Rlp::new(&mixed)), Err(BlockDownloaderImportError::Invalid(InvalidReason::BodiesMismatch)));
+		assert_eq!(downloader.peek_ready().map(|(_, n)| n).collect::<Vec<_>>(), vec![0]);
+
+		assert_eq!(downloader.import_bodies(&valid), Ok(ResponseQuality::Useful));
+		assert_eq!(downloader.peek_ready().map(|(_, n)| n).collect::<Vec<_>>(), vec![0, 1, 2]);
+	}
+
+	#[test]
+	fn stops_requesting_receipts_when_disabled() {
+		let (headers, bodies, _) = blocks_with_receipts();
+		let genesis = headers[0].hash();
+		let mut stream = RlpStream::new_list(headers.len());
+		for header in &headers {
+			stream.append(header);
+		}
+		let headers_rlp = stream.out();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(true, &genesis, 0);
+		downloader.reset_to(vec![genesis]);
+		downloader.import_headers(&mut io, &Rlp::new(&headers_rlp), Some(genesis)).unwrap();
+
+		let hashes = vec![headers[1].hash(), headers[2].hash()];
+		assert_eq!(downloader.request_blocks(&mut io, 0), Some(BlockRequest::Bodies { hashes: hashes.clone() }));
+		let mut response = RlpStream::new_list(bodies.len());
+		for body in &bodies {
+			response.append_raw(body, 1);
+		}
+		downloader.import_bodies(&Rlp::new(&response.out())).unwrap();
+		assert_eq!(downloader.plan_requests(1), vec![BlockRequest::Receipts { hashes: hashes.clone() }]);
+
+		downloader.set_download_receipts(false);
+		assert_eq!(downloader.plan_requests(1), vec![BlockRequest::Headers { start: headers[2].hash(), count: 128, skip: 0, subchain: Some(headers[2].hash()) }]);
+		match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Receipts { .. }) => panic!("receipts requested after disabling them"),
+			_ => (),
+		}
+		assert_eq!(downloader.peek_ready().map(|(_, n)| n).collect::<Vec<_>>(), vec![0, 1, 2]);
+
+		downloader.set_download_receipts(true);
+		assert_eq!(downloader.plan_requests(1), vec![BlockRequest::Receipts { hashes }]);
+	}
+
+	#[test]
+	fn headers_requests_name_their_subchain() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(100, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let hash = |n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Headers { subchain, .. }) => assert_eq!(subchain, None),
+			_ => panic!("expected a subchain heads request"),
+		}
+
+		downloader.reset_to(vec![genesis, hash(50)]);
+		let subchain = |request: Option<BlockRequest>| match request {
+			Some(BlockRequest::Headers { subchain, .. }) => subchain,
+			_ => panic!("expected a headers request"),
+		};
+		assert_eq!(subchain(downloader.request_blocks(&mut io, 0)), Some(genesis));
+		assert_eq!(subchain(downloader.request_blocks(&mut io, 0)), Some(hash(50)));
+		import_headers(&mut downloader, &mut io, &remote, 50, 10).unwrap();
+		assert_eq!(downloader.subchain_heads(), vec![genesis, hash(59)]);
+		assert_eq!(subchain(downloader.request_blocks(&mut io, 0)), Some(hash(59)));
+	}
+
+	#[test]
+	fn check_invariants_catches_inconsistent_state() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(100, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+		let hash = |n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let mut downloader = BlockDownloader::new(false, &genesis, 0);
+		assert_eq!(downloader.check_invariants(), Ok(()));
+		downloader.reset_to(vec![genesis]);
+		import_headers(&mut downloader, &mut io, &remote, 0, 10).unwrap();
+		assert_eq!(downloader.check_invariants(), Ok(()));
+		downloader.pause();
+		assert_eq!(downloader.check_invariants(), Ok(()));
+		downloader.resume();
+
+		// Blocks left behind when going back to idle.
+		downloader.state = State::Idle;
+		assert!(downloader.check_invariants().is_err());
+		downloader.reset(ResetReason::Requested);
+		assert_eq!(downloader.check_invariants(), Ok(()));
+
+		// Paused without a state to resume.
+		downloader.state = State::Paused;
+		assert!(downloader.check_invariants().is_err());
+		downloader.state = State::Idle;
+
+		// Last imported block moved below the round start.
+		downloader.last_round_start = downloader.last_imported_block + 1;
+		assert!(downloader.check_invariants().is_err());
+		downloader.last_round_start = downloader.last_imported_block;
+
+		// Too many round parents.
+		for n in 0..(MAX_ROUND_PARENTS as u64 + 1) {
+			downloader.round_parents.push_back((hash(n + 1), hash(n)));
+		}
+		assert!(downloader.check_invariants().is_err());
+	}
+
+	#[test]
+	fn known_blocks_count_as_progress_when_configured() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let hash = |n| (&remote as &BlockChainClient).block_hash(BlockId::Number(n)).unwrap();
+
+		let env = TestEnv::new();
+		env.chain.add_blocks(5, EachBlockWith::Nothing);
+		env.chain.reject_known_blocks.store(true, ::std::sync::atomic::Ordering::Relaxed);
+		let mut io = env.io();
+
+		let mut starts = Vec::new();
+		for count_known_blocks in &[false, true] {
+			let config = BlockDownloaderConfig { count_known_blocks: *count_known_blocks, ..Default::default() };
+			let mut downloader = BlockDownloader::new_with_config(false, &hash(2), 2, config);
+			downloader.reset_to(vec![hash(2)]);
+			import_headers(&mut downloader, &mut io, &remote, 2, 4).unwrap();
+			downloader.collect_blocks(&mut io, false).unwrap();
+			assert_eq!(downloader.last_imported_block, 5);
+			match downloader.request_blocks(&mut io, 0) {
+				Some(BlockRequest::Headers { start, .. }) => starts.push(start),
+				_ => panic!("expected a headers request"),
+			}
+		}
+		// Blocks 2 to 5 were all in chain already: by default the next round steps back before block 2.
+		assert_eq!(starts, vec![hash(1), hash(5)]);
+	}
+
+	#[test]
+	fn header_limit_below_two_is_raised() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Nothing);
+		let genesis = remote.genesis_hash;
+
+		let env = TestEnv::new();
+		let mut io = env.io();
+
+		let config = BlockDownloaderConfig { max_headers_to_request: 0, ..Default::default() };
+		let mut downloader = BlockDownloader::new_with_config(false, &genesis, 0, config);
+		match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Headers { skip, .. }) => assert_eq!(skip, 0),
+			_ => panic!("expected a headers request"),
+		}
+		downloader.reset_to(vec![genesis]);
+		match downloader.request_blocks(&mut io, 0) {
+			Some(BlockRequest::Headers { count, .. }) => assert_eq!(count, 2),
+			_ => panic!("expected a headers request"),
+		}
+	}
+
+	#[test]
+	fn zero_retract_step_limit_still_steps_back() {
+		let env = TestEnv::new();
+		env.chain.add_blocks(10, EachBlockWith::Nothing);
+		let best = (&env.chain as &BlockChainClient).block_hash(BlockId::Number(10)).unwrap();
+		let mut io = env.io();
+
+		let config = BlockDownloaderConfig { max_retract_step: 0, ..Default::default() };
+		let mut downloader = BlockDownloader::new_with_config(false, &best, 10, config);
+		downloader.limit_reorg = false;
+		for expected in (7..10).rev() {
+			downloader.imported_this_round = Some(0);
+			downloader.start_sync_round(&mut io);
+			assert_eq!(downloader.last_imported_block, expected);
+		}
+	}
+}
@@ -22,7 +22,7 @@ use triehash_ethereum::ordered_trie_root;
 use bytes::Bytes;
 use rlp::{Rlp, RlpStream, DecoderError};
 use network;
-use ethcore::header::Header as BlockHeader;
+use ethcore::header::{BlockNumber, Header as BlockHeader};
 use ethcore::verification::queue::kind::blocks::Unverified;
 use transaction::UnverifiedTransaction;
 
@@ -75,6 +75,13 @@ impl SyncBody {
 		Ok(result)
 	}
 
+	fn header_id(&self) -> HeaderId {
+		HeaderId {
+			transactions_root: ordered_trie_root(Rlp::new(&self.transactions_bytes).iter().map(|r| r.as_raw())),
+			uncles: keccak(&self.uncles_bytes),
+		}
+	}
+
 	fn empty_body() -> Self {
 		SyncBody {
 			transactions_bytes: ::rlp::EMPTY_LIST_RLP.to_vec(),
@@ -131,6 +138,15 @@ pub struct BlockAndReceipts {
 	pub receipts: Option<Bytes>,
 }
 
+/// Order in which block bodies are requested.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum BodiesOrder {
+	/// Lowest pending blocks first, so that they can be imported as soon as possible.
+	Chain,
+	/// Highest pending blocks first.
+	NewestFirst,
+}
+
 /// Used to identify header by transactions and uncles hashes
 #[derive(Eq, PartialEq, Hash)]
 struct HeaderId {
@@ -182,6 +198,30 @@ impl BlockCollection {
 		}
 	}
 
+	/// Start or stop downloading block receipts. Stopping drops the receipts downloaded so far,
+	/// so that all drained blocks are imported the same way.
+	pub fn set_need_receipts(&mut self, need_receipts: bool) {
+		if need_receipts == self.need_receipts {
+			return;
+		}
+		self.need_receipts = need_receipts;
+		self.receipt_ids.clear();
+		self.downloading_receipts.clear();
+		for (hash, block) in self.blocks.iter_mut() {
+			if need_receipts {
+				block.receipts_root = *block.header.header.receipts_root();
+				if block.receipts_root == KECCAK_NULL_RLP {
+					block.receipts = Some(RlpStream::new_list(0).out());
+				} else {
+					self.receipt_ids.entry(block.receipts_root).or_insert_with(Vec::new).push(*hash);
+				}
+			} else {
+				block.receipts = None;
+				block.receipts_root = H256::new();
+			}
+		}
+	}
+
 	/// Clear everything.
 	pub fn clear(&mut self) {
 		self.blocks.clear();
@@ -198,7 +238,12 @@ impl BlockCollection {
 	/// Reset collection for a new sync round with given subchain block hashes.
 	pub fn reset_to(&mut self, hashes: Vec<H256>) {
 		self.clear();
-		self.heads = hashes;
+		let count = hashes.len();
+		let mut unique = HashSet::new();
+		self.heads = hashes.into_iter().filter(|h| unique.insert(*h)).collect();
+		if self.heads.len() != count {
+			trace!(target: "sync", "Dropped {} duplicate subchain heads", count - self.heads.len());
+		}
 	}
 
 	/// Insert a set of headers into collection and advance subchain head pointers.
@@ -211,6 +256,24 @@ impl BlockCollection {
 		self.update_heads();
 	}
 
+	/// Insert consecutive headers that continue the imported chain and end below the downloaded subchains.
+	/// Blocks are drained starting from the first of them.
+	pub fn insert_range_headers(&mut self, headers: Vec<SyncHeader>) {
+		if let Some(first) = headers.first() {
+			self.head = Some(*first.header.parent_hash());
+		}
+		self.insert_headers(headers);
+	}
+
+	/// Check that each of the bodies belongs to a different downloaded header still missing its body.
+	pub fn check_bodies(&self, bodies: &[SyncBody]) -> bool {
+		let mut ids = HashSet::new();
+		bodies.iter().all(|body| {
+			let header_id = body.header_id();
+			self.header_ids.contains_key(&header_id) && ids.insert(header_id)
+		})
+	}
+
 	/// Insert a collection of block bodies for previously downloaded headers.
 	pub fn insert_bodies(&mut self, bodies: Vec<SyncBody>) -> usize {
 		let mut inserted = 0;
@@ -241,19 +304,37 @@ impl BlockCollection {
 	}
 
 	/// Returns a set of block hashes that require a body download. The returned set is marked as being downloaded.
-	pub fn needed_bodies(&mut self, count: usize, _ignore_downloading: bool) -> Vec<H256> {
+	pub fn needed_bodies(&mut self, count: usize, _ignore_downloading: bool, order: BodiesOrder) -> Vec<H256> {
+		let needed_bodies = self.pending_bodies(count, &HashSet::new(), order);
+		self.downloading_bodies.extend(needed_bodies.iter().cloned());
+		needed_bodies
+	}
+
+	/// Returns a set of block hashes that require a body download, skipping those in `exclude`.
+	/// Nothing is marked as being downloaded.
+	pub fn pending_bodies(&self, count: usize, exclude: &HashSet<H256>, order: BodiesOrder) -> Vec<H256> {
 		if self.head.is_none() {
 			return Vec::new();
 		}
+		if order == BodiesOrder::NewestFirst {
+			let mut pending: Vec<(BlockNumber, H256)> = self.blocks.iter()
+				.filter(|&(h, block)| block.body.is_none() && !self.downloading_bodies.contains(h) && !exclude.contains(h))
+				.map(|(h, block)| (block.header.header.number(), *h))
+				.collect();
+			pending.sort_by(|a, b| b.cmp(a));
+			return pending.into_iter().take(count).map(|(_, h)| h).collect();
+		}
 		let mut needed_bodies: Vec<H256> = Vec::new();
+		let mut taken = HashSet::new();
 		let mut head = self.head;
 		while head.is_some() && needed_bodies.len() < count {
 			head = self.parents.get(&head.unwrap()).cloned();
 			if let Some(head) = head {
 				match self.blocks.get(&head) {
-					Some(block) if block.body.is_none() && !self.downloading_bodies.contains(&head) => {
-						self.downloading_bodies.insert(head.clone());
-						needed_bodies.push(head.clone());
+					Some(block) if block.body.is_none() && !self.downloading_bodies.contains(&head) && !exclude.contains(&head) => {
+						if taken.insert(head) {
+							needed_bodies.push(head.clone());
+						}
 					}
 					_ => (),
 				}
@@ -263,9 +344,8 @@ impl BlockCollection {
 			if needed_bodies.len() >= count {
 				break;
 			}
-			if !self.downloading_bodies.contains(h) {
+			if !self.downloading_bodies.contains(h) && !exclude.contains(h) && taken.insert(*h) {
 				needed_bodies.push(h.clone());
-				self.downloading_bodies.insert(h.clone());
 			}
 		}
 		needed_bodies
@@ -273,19 +353,28 @@ impl BlockCollection {
 
 	/// Returns a set of block hashes that require a receipt download. The returned set is marked as being downloaded.
 	pub fn needed_receipts(&mut self, count: usize, _ignore_downloading: bool) -> Vec<H256> {
+		let needed_receipts = self.pending_receipts(count, &HashSet::new());
+		self.downloading_receipts.extend(needed_receipts.iter().map(|&(_, root)| root));
+		needed_receipts.into_iter().map(|(h, _)| h).collect()
+	}
+
+	/// Returns a set of (block hash, receipts root) pairs that require a receipt download,
+	/// skipping receipts roots in `exclude`. Nothing is marked as being downloaded.
+	pub fn pending_receipts(&self, count: usize, exclude: &HashSet<H256>) -> Vec<(H256, H256)> {
 		if self.head.is_none() || !self.need_receipts {
 			return Vec::new();
 		}
-		let mut needed_receipts: Vec<H256> = Vec::new();
+		let mut needed_receipts: Vec<(H256, H256)> = Vec::new();
+		let mut taken = HashSet::new();
 		let mut head = self.head;
 		while head.is_some() && needed_receipts.len() < count {
 			head = self.parents.get(&head.unwrap()).cloned();
 			if let Some(head) = head {
 				match self.blocks.get(&head) {
 					Some(block) => {
-						if block.receipts.is_none() && !self.downloading_receipts.contains(&block.receipts_root) {
-							self.downloading_receipts.insert(block.receipts_root);
-							needed_receipts.push(head.clone());
+						let root = block.receipts_root;
+						if block.receipts.is_none() && !self.downloading_receipts.contains(&root) && !exclude.contains(&root) && taken.insert(root) {
+							needed_receipts.push((head.clone(), root));
 						}
 					}
 					_ => (),
@@ -297,9 +386,8 @@ impl BlockCollection {
 			if needed_receipts.len() >= count {
 				break;
 			}
-			if !self.downloading_receipts.contains(root) {
-				needed_receipts.push(h.clone());
-				self.downloading_receipts.insert(*root);
+			if !self.downloading_receipts.contains(root) && !exclude.contains(root) && taken.insert(*root) {
+				needed_receipts.push((h.clone(), *root));
 			}
 		}
 		needed_receipts
@@ -321,6 +409,50 @@ impl BlockCollection {
 		download.map(|h| (h, count))
 	}
 
+	/// Returns the head of the first subchain if none of its headers have been downloaded or requested yet.
+	pub fn first_pending_head(&self) -> Option<H256> {
+		match self.heads.first() {
+			Some(h) if self.head.is_none() && !self.downloading_headers.contains(h) => Some(*h),
+			_ => None,
+		}
+	}
+
+	/// Moves the start of the first subchain to `hash`, a descendant of the current start.
+	pub fn advance_first_head(&mut self, hash: H256) {
+		if let Some(first) = self.heads.first_mut() {
+			*first = hash;
+		}
+	}
+
+	/// Check if `hash` is the start of a subchain.
+	pub fn is_head(&self, hash: &H256) -> bool {
+		self.heads.contains(hash)
+	}
+
+	/// Returns subchain heads that require a header download. Nothing is marked as being downloaded.
+	pub fn pending_headers(&self) -> Vec<H256> {
+		self.heads.iter().filter(|h| !self.downloading_headers.contains(h)).cloned().collect()
+	}
+
+	/// Mark header as being downloaded.
+	pub fn mark_header_download(&mut self, hash: &H256) {
+		self.downloading_headers.insert(hash.clone());
+	}
+
+	/// Mark block bodies as being downloaded.
+	pub fn mark_body_download(&mut self, hashes: &[H256]) {
+		self.downloading_bodies.extend(hashes.iter().cloned());
+	}
+
+	/// Mark block receipts as being downloaded.
+	pub fn mark_receipt_download(&mut self, hashes: &[H256]) {
+		for h in hashes {
+			if let Some(ref block) = self.blocks.get(h) {
+				self.downloading_receipts.insert(block.receipts_root);
+			}
+		}
+	}
+
 	/// Unmark header as being downloaded.
 	pub fn clear_header_download(&mut self, hash: &H256) {
 		self.downloading_headers.remove(hash);
@@ -342,7 +474,22 @@ impl BlockCollection {
 		}
 	}
 
-	/// Get a valid chain of blocks ordered in ascending order and ready for importing into blockchain.
+	/// Unmark block receipts that are still missing as being downloaded, whether the block bodies are
+	/// downloaded or not. Returns the number of receipts that can be requested again.
+	pub fn requeue_receipts(&mut self, hashes: &[H256]) -> usize {
+		let mut requeued = 0;
+		for h in hashes {
+			if let Some(ref block) = self.blocks.get(h) {
+				if block.receipts.is_none() && self.downloading_receipts.remove(&block.receipts_root) {
+					requeued += 1;
+				}
+			}
+		}
+		requeued
+	}
+
+	/// Get a valid chain of blocks ready for importing into blockchain. Each block is the parent of the next one,
+	/// so the blocks are in strictly ascending number order however their headers were downloaded.
 	pub fn drain(&mut self) -> Vec<BlockAndReceipts> {
 		if self.blocks.is_empty() || self.head.is_none() {
 			return Vec::new();
@@ -383,16 +530,106 @@ impl BlockCollection {
 			}
 		}
 
+		debug_assert!(drained.windows(2).all(|w| w[1].block.header.parent_hash() == &w[0].block.header.hash()));
 		trace!(target: "sync", "Drained {} blocks, new head :{:?}", drained.len(), self.head);
 		drained
 	}
 
+	/// Returns the blocks that `drain` would return, without removing them.
+	pub fn ready_blocks<'a>(&'a self) -> impl Iterator<Item = (&'a H256, BlockNumber)> + 'a {
+		let mut ready = Vec::new();
+		let mut head = self.head.as_ref();
+		while let Some(h) = head {
+			head = self.parents.get(h);
+			if let Some(head) = head {
+				match self.blocks.get(head) {
+					Some(block) if block.body.is_some() && (!self.need_receipts || block.receipts.is_some()) => {
+						ready.push((head, block.header.header.number()));
+					},
+					_ => break,
+				}
+			}
+		}
+		ready.into_iter()
+	}
+
 	/// Check if the collection is empty. We consider the syncing round complete once
 	/// there is no block data left and only a single or none head pointer remains.
 	pub fn is_empty(&self) -> bool {
 		self.heads.len() == 0 || (self.heads.len() == 1 && self.head.map_or(false, |h| h == self.heads[0]))
 	}
 
+	/// Remove a subchain head together with all the blocks downloaded for it.
+	/// Returns `false` if there is no such head.
+	pub fn remove_head(&mut self, head: &H256) -> bool {
+		let index = match self.heads.iter().position(|h| h == head) {
+			Some(index) => index,
+			None => return false,
+		};
+		self.heads.remove(index);
+		self.downloading_headers.remove(head);
+
+		// Subchains are merged as soon as they connect, so walking back from the head only visits this subchain.
+		let mut hash = *head;
+		while let Some(block) = self.blocks.remove(&hash) {
+			let header = &block.header.header;
+			let parent = *header.parent_hash();
+			self.parents.remove(&parent);
+			self.downloading_bodies.remove(&hash);
+			let header_id = HeaderId {
+				transactions_root: *header.transactions_root(),
+				uncles: *header.uncles_hash(),
+			};
+			if self.header_ids.get(&header_id) == Some(&hash) {
+				self.header_ids.remove(&header_id);
+			}
+			let receipts_done = match self.receipt_ids.get_mut(&block.receipts_root) {
+				Some(hashes) => {
+					hashes.retain(|h| h != &hash);
+					hashes.is_empty()
+				},
+				None => false,
+			};
+			if receipts_done {
+				self.receipt_ids.remove(&block.receipts_root);
+				self.downloading_receipts.remove(&block.receipts_root);
+			}
+			trace!(target: "sync", "Removed block {:?} of cancelled subchain {:?}", hash, head);
+			hash = parent;
+		}
+		true
+	}
+
+	/// Return the number of subchain heads.
+	pub fn heads_len(&self) -> usize {
+		self.heads.len()
+	}
+
+	/// Return the subchain heads.
+	pub fn heads(&self) -> &[H256] {
+		&self.heads
+	}
+
+	/// Return the number of blocks held in the collection.
+	pub fn block_count(&self) -> usize {
+		self.blocks.len()
+	}
+
+	/// Return the highest block number held in the collection.
+	pub fn highest_number(&self) -> Option<BlockNumber> {
+		self.blocks.values().map(|block| block.header.header.number()).max()
+	}
+
+	/// Return the lowest block number held in the collection.
+	pub fn lowest_number(&self) -> Option<BlockNumber> {
+		self.blocks.values().map(|block| block.header.header.number()).min()
+	}
+
+	/// Return the number of a block held in the collection.
+	pub fn block_number(&self, hash: &H256) -> Option<BlockNumber> {
+		self.blocks.get(hash).map(|block| block.header.header.number())
+	}
+
 	/// Check if collection contains a block header.
 	pub fn contains(&self, hash: &H256) -> bool {
 		self.blocks.contains_key(hash)
@@ -419,14 +656,7 @@ impl BlockCollection {
 	}
 
 	fn insert_body(&mut self, body: SyncBody) -> Result<(), network::Error> {
-		let header_id = {
-			let tx_root = ordered_trie_root(Rlp::new(&body.transactions_bytes).iter().map(|r| r.as_raw()));
-			let uncles = keccak(&body.uncles_bytes);
-			HeaderId {
-				transactions_root: tx_root,
-				uncles: uncles
-			}
-		};
+		let header_id = body.header_id();
 
 		match self.header_ids.remove(&header_id) {
 			Some(h) => {
@@ -572,7 +802,7 @@ impl BlockCollection {
 
 #[cfg(test)]
 mod test {
-	use super::{BlockCollection, SyncHeader};
+	use super::{BlockCollection, BodiesOrder, SyncHeader};
 	use ethcore::client::{TestBlockChainClient, EachBlockWith, BlockId, BlockChainClient};
 	use ethcore::header::BlockNumber;
 	use ethcore::verification::queue::kind::blocks::Unverified;
@@ -617,7 +847,7 @@ mod test {
 		bc.reset_to(heads);
 		assert!(!bc.is_empty());
 		assert_eq!(hashes[0], bc.heads[0]);
-		assert!(bc.needed_bodies(1, false).is_empty());
+		assert!(bc.needed_bodies(1, false, BodiesOrder::Chain).is_empty());
 		assert!(!bc.contains(&hashes[0]));
 		assert!(!bc.is_downloading(&hashes[0]));
 
@@ -707,4 +937,15 @@ mod test {
 		bc.insert_headers(headers[0..1].into_iter().map(Clone::clone).collect());
 		assert_eq!(bc.drain().len(), 2);
 	}
+
+	#[test]
+	fn reset_to_drops_duplicate_heads() {
+		let mut bc = BlockCollection::new(false);
+		let client = TestBlockChainClient::new();
+		client.add_blocks(100, EachBlockWith::Nothing);
+		let hashes: Vec<_> = (0 .. 100).map(|i| (&client as &BlockChainClient).block_hash(BlockId::Number(i)).unwrap()).collect();
+		bc.reset_to(vec![hashes[0], hashes[10], hashes[0], hashes[20], hashes[10]]);
+		assert_eq!(bc.heads_len(), 3);
+		assert_eq!(bc.heads(), &[hashes[0], hashes[10], hashes[20]][..]);
+	}
 }
@@ -15,7 +15,7 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 use api::WARP_SYNC_PROTOCOL_ID;
-use block_sync::{BlockDownloaderImportError as DownloaderImportError, DownloadAction};
+use block_sync::{BlockDownloaderImportError as DownloaderImportError, DownloadAction, InvalidReason, ResponseQuality};
 use bytes::Bytes;
 use ethcore::client::{BlockId, BlockStatus};
 use ethcore::error::{Error as EthcoreError, ErrorKind as EthcoreErrorKind, ImportErrorKind, BlockError};
@@ -90,8 +90,8 @@ impl SyncHandler {
 		};
 
 		match result {
-			Err(DownloaderImportError::Invalid) => {
-				debug!(target:"sync", "{} -> Invalid packet {}", peer, packet_id);
+			Err(DownloaderImportError::Invalid(reason)) => {
+				debug!(target:"sync", "{} -> Invalid packet {}: {:?}", peer, packet_id, reason);
 				io.disable_peer(peer);
 				sync.deactivate_peer(io, peer);
 			},
@@ -179,7 +179,7 @@ impl SyncHandler {
 		let last_imported_number = sync.new_blocks.last_imported_block_number();
 		if last_imported_number > number && last_imported_number - number > MAX_NEW_BLOCK_AGE {
 			trace!(target: "sync", "Ignored ancient new block {:?}", hash);
-			return Err(DownloaderImportError::Invalid);
+			return Err(DownloaderImportError::Invalid(InvalidReason::StaleBlock));
 		}
 		match io.chain().import_block(block) {
 			Err(EthcoreError(EthcoreErrorKind::Import(ImportErrorKind::AlreadyInChain), _)) => {
@@ -200,7 +200,7 @@ impl SyncHandler {
 			},
 			Err(e) => {
 				debug!(target: "sync", "Bad new block {:?} : {:?}", hash, e);
-				return Err(DownloaderImportError::Invalid);
+				return Err(DownloaderImportError::Invalid(InvalidReason::BadBlock));
 			}
 		};
 		if unknown {
@@ -252,7 +252,7 @@ impl SyncHandler {
 			}
 			if last_imported_number > number && last_imported_number - number > MAX_NEW_BLOCK_AGE {
 				trace!(target: "sync", "Ignored ancient new block hash {:?}", hash);
-				return Err(DownloaderImportError::Invalid);
+				return Err(DownloaderImportError::Invalid(InvalidReason::StaleBlock));
 			}
 			match io.chain().block_status(BlockId::Hash(hash.clone())) {
 				BlockStatus::InChain  => {
@@ -273,7 +273,7 @@ impl SyncHandler {
 				},
 				BlockStatus::Bad => {
 					debug!(target: "sync", "Bad new block hash {:?}", hash);
-					return Err(DownloaderImportError::Invalid);
+					return Err(DownloaderImportError::Invalid(InvalidReason::KnownBadBlock));
 				}
 			}
 		};
@@ -298,13 +298,11 @@ impl SyncHandler {
 		}
 		let item_count = r.item_count()?;
 		trace!(target: "sync", "{} -> BlockBodies ({} entries), set = {:?}", peer_id, item_count, block_set);
-		if item_count == 0 {
-			Err(DownloaderImportError::Useless)
-		} else if sync.state == SyncState::Waiting {
+		if sync.state == SyncState::Waiting {
 			trace!(target: "sync", "Ignored block bodies while waiting");
 			Ok(())
 		} else {
-			{
+			let quality = {
 				let downloader = match block_set {
 					BlockSet::NewBlocks => &mut sync.new_blocks,
 					BlockSet::OldBlocks => match sync.old_blocks {
@@ -315,9 +313,16 @@ impl SyncHandler {
 						Some(ref mut blocks) => blocks,
 					}
 				};
-				downloader.import_bodies(r)?;
+				downloader.import_bodies(r)?
+			};
+			trace!(target: "sync", "{} -> BlockBodies response quality: {:?}", peer_id, quality);
+			if quality == ResponseQuality::Useless {
+				// The peer doesn't have the bodies yet; leave them to other peers for this round.
+				sync.deactivate_peer(io, peer_id);
+			}
+			if item_count != 0 {
+				sync.collect_blocks(io, block_set);
 			}
-			sync.collect_blocks(io, block_set);
 			Ok(())
 		}
 	}
@@ -337,7 +342,7 @@ impl SyncHandler {
 				let header = r.at(0)?.as_raw();
 				if keccak(&header) != fork_hash {
 					trace!(target: "sync", "{}: Fork mismatch", peer_id);
-					return Err(DownloaderImportError::Invalid);
+					return Err(DownloaderImportError::Invalid(InvalidReason::WrongFork));
 				}
 
 				trace!(target: "sync", "{}: Confirmed peer", peer_id);
@@ -364,11 +369,15 @@ impl SyncHandler {
 			return SyncHandler::on_peer_fork_header(sync, io, peer_id, r);
 		}
 
+		let requested_range = match sync.peers.get(&peer_id).and_then(|p| p.block_set) {
+			Some(BlockSet::OldBlocks) => sync.old_blocks.as_ref().and_then(|d| d.header_range_request()),
+			_ => sync.new_blocks.header_range_request(),
+		};
 		sync.clear_peer_download(peer_id);
 		let expected_hash = sync.peers.get(&peer_id).and_then(|p| p.asking_hash);
 		let allowed = sync.peers.get(&peer_id).map(|p| p.is_allowed()).unwrap_or(false);
 		let block_set = sync.peers.get(&peer_id).and_then(|p| p.block_set).unwrap_or(BlockSet::NewBlocks);
-		if !sync.reset_peer_asking(peer_id, PeerAsking::BlockHeaders) || expected_hash.is_none() || !allowed {
+		if !sync.reset_peer_asking(peer_id, PeerAsking::BlockHeaders) || !allowed {
 			trace!(target: "sync", "{}: Ignored unexpected headers, expected_hash = {:?}", peer_id, expected_hash);
 			return Ok(());
 		}
@@ -383,7 +392,7 @@ impl SyncHandler {
 			return Ok(());
 		}
 
-		let result = {
+		let (result, quality) = {
 			let downloader = match block_set {
 				BlockSet::NewBlocks => &mut sync.new_blocks,
 				BlockSet::OldBlocks => {
@@ -396,8 +405,17 @@ impl SyncHandler {
 					}
 				}
 			};
-			downloader.import_headers(io, r, expected_hash)?
+			// Headers requested by number to fill a gap are the only ones requested without a hash.
+			match (expected_hash, requested_range) {
+				(Some(_), _) => downloader.import_headers(io, r, expected_hash)?,
+				(None, Some((start_number, count))) => downloader.import_header_range(io, r, start_number, count)?,
+				(None, None) => {
+					trace!(target: "sync", "{}: Ignored unexpected headers", peer_id);
+					return Ok(());
+				},
+			}
 		};
+		trace!(target: "sync", "{} -> BlockHeaders response quality: {:?}", peer_id, quality);
 
 		if let DownloadAction::Reset = result {
 			// mark all outstanding requests as expired
@@ -413,6 +431,7 @@ impl SyncHandler {
 
 	/// Called by peer once it has new block receipts
 	fn on_peer_block_receipts(sync: &mut ChainSync, io: &mut SyncIo, peer_id: PeerId, r: &Rlp) -> Result<(), DownloaderImportError> {
+		let requested = sync.peers.get(&peer_id).map_or_else(Vec::new, |p| p.asking_blocks.clone());
 		sync.clear_peer_download(peer_id);
 		let block_set = sync.peers.get(&peer_id).and_then(|p| p.block_set).unwrap_or(BlockSet::NewBlocks);
 		if !sync.reset_peer_asking(peer_id, PeerAsking::BlockReceipts) {
@@ -438,7 +457,7 @@ impl SyncHandler {
 						Some(ref mut blocks) => blocks,
 					}
 				};
-				downloader.import_receipts(io, r)?;
+				downloader.import_receipts(io, r, &requested)?;
 			}
 			sync.collect_blocks(io, block_set);
 			Ok(())
@@ -465,7 +484,7 @@ impl SyncHandler {
 
 		if !is_supported_version {
 			trace!(target: "sync", "{}: Snapshot manifest version not supported: {}", peer_id, manifest.version);
-			return Err(DownloaderImportError::Invalid);
+			return Err(DownloaderImportError::Invalid(InvalidReason::UnsupportedSnapshot));
 		}
 		sync.snapshot.reset_to(&manifest, &keccak(manifest_rlp.as_raw()));
 		io.snapshot_service().begin_restore(manifest);
@@ -575,11 +594,11 @@ impl SyncHandler {
 		let chain_info = io.chain().chain_info();
 		if peer.genesis != chain_info.genesis_hash {
 			trace!(target: "sync", "Peer {} genesis hash mismatch (ours: {}, theirs: {})", peer_id, chain_info.genesis_hash, peer.genesis);
-			return Err(DownloaderImportError::Invalid);
+			return Err(DownloaderImportError::Invalid(InvalidReason::IncompatibleStatus));
 		}
 		if peer.network_id != sync.network_id {
 			trace!(target: "sync", "Peer {} network id mismatch (ours: {}, theirs: {})", peer_id, sync.network_id, peer.network_id);
-			return Err(DownloaderImportError::Invalid);
+			return Err(DownloaderImportError::Invalid(InvalidReason::IncompatibleStatus));
 		}
 
 		if false
@@ -587,7 +606,7 @@ impl SyncHandler {
 			|| (!warp_protocol && (peer.protocol_version < ETH_PROTOCOL_VERSION_62.0 || peer.protocol_version > ETH_PROTOCOL_VERSION_63.0))
 		{
 			trace!(target: "sync", "Peer {} unsupported eth protocol ({})", peer_id, peer.protocol_version);
-			return Err(DownloaderImportError::Invalid);
+			return Err(DownloaderImportError::Invalid(InvalidReason::IncompatibleStatus));
 		}
 
 		if sync.sync_start_time.is_none() {
@@ -681,7 +700,7 @@ impl SyncHandler {
 mod tests {
 	use ethcore::client::{ChainInfo, EachBlockWith, TestBlockChainClient};
 	use parking_lot::RwLock;
-	use rlp::{Rlp};
+	use rlp::{Rlp, RlpStream};
 	use std::collections::{VecDeque};
 	use tests::helpers::{TestIo};
 	use tests::snapshot::TestSnapshotService;
@@ -689,10 +708,12 @@ mod tests {
 	use super::*;
 	use super::super::tests::{
 		dummy_sync_with_peer,
+		insert_dummy_peer,
 		get_dummy_block,
 		get_dummy_blocks,
 		get_dummy_hashes,
 	};
+	use super::super::GET_BLOCK_BODIES_PACKET;
 
 	#[test]
 	fn handles_peer_new_hashes() {
@@ -783,4 +804,43 @@ mod tests {
 
 		assert!(result.is_ok());
 	}
+
+	#[test]
+	fn empty_bodies_at_chain_head_are_requested_from_another_peer() {
+		let remote = TestBlockChainClient::new();
+		remote.add_blocks(10, EachBlockWith::Transaction);
+		let genesis = remote.genesis_hash;
+		let remote_best = remote.chain_info().best_block_hash;
+
+		let local = TestBlockChainClient::new();
+		let queue = RwLock::new(VecDeque::new());
+		let ss = TestSnapshotService::new();
+		let mut io = TestIo::new(&local, &ss, &queue, None);
+
+		let mut sync = dummy_sync_with_peer(remote_best, &local);
+		insert_dummy_peer(&mut sync, 1, remote_best);
+		sync.active_peers = vec![0, 1].into_iter().collect();
+		sync.state = SyncState::Blocks;
+
+		let mut headers = RlpStream::new_list(10);
+		for n in 0..10 {
+			headers.append_raw(&remote.block_header(BlockId::Number(n)).unwrap().into_inner(), 1);
+		}
+		sync.new_blocks.reset_to(vec![genesis]);
+		sync.new_blocks.request_blocks(&mut io, 0);
+		sync.new_blocks.import_headers(&mut io, &Rlp::new(&headers.out()), Some(genesis)).unwrap();
+
+		sync.sync_peer(&mut io, 0, false);
+		assert_eq!(io.packets.len(), 1);
+		assert_eq!(io.packets[0].packet_id, GET_BLOCK_BODIES_PACKET);
+		assert_eq!(io.packets[0].recipient, 0);
+
+		let empty = RlpStream::new_list(0).out();
+		SyncHandler::on_packet(&mut sync, &mut io, 0, BLOCK_BODIES_PACKET, &empty);
+
+		assert!(!sync.active_peers.contains(&0));
+		assert_eq!(io.packets.len(), 2);
+		assert_eq!(io.packets[1].packet_id, GET_BLOCK_BODIES_PACKET);
+		assert_eq!(io.packets[1].recipient, 1);
+	}
 }
@@ -109,7 +109,7 @@ use ethcore::client::{BlockChainClient, BlockStatus, BlockId, BlockChainInfo, Bl
 use ethcore::snapshot::{RestorationStatus};
 use sync_io::SyncIo;
 use super::{WarpSync, SyncConfig};
-use block_sync::{BlockDownloader, BlockDownloaderImportError as DownloaderImportError};
+use block_sync::{BlockDownloader, BlockDownloaderImportError as DownloaderImportError, ResetReason};
 use rand::Rng;
 use snapshot::{Snapshot};
 use api::{EthProtocolInfo as PeerInfoDigest, WARP_SYNC_PROTOCOL_ID};
@@ -429,7 +429,7 @@ impl ChainSync {
 			peers: HashMap::new(),
 			handshaking_peers: HashMap::new(),
 			active_peers: HashSet::new(),
-			new_blocks: BlockDownloader::new(false, &chain_info.best_block_hash, chain_info.best_block_number),
+			new_blocks: BlockDownloader::for_new_blocks(&chain_info.best_block_hash, chain_info.best_block_number),
 			old_blocks: None,
 			last_sent_block_number: 0,
 			network_id: config.network_id,
@@ -509,7 +509,7 @@ impl ChainSync {
 
 	/// Reset sync. Clear all downloaded data but keep the queue
 	fn reset(&mut self, io: &mut SyncIo) {
-		self.new_blocks.reset();
+		self.new_blocks.reset(ResetReason::Requested);
 		let chain_info = io.chain().chain_info();
 		for (_, ref mut p) in &mut self.peers {
 			if p.block_set != Some(BlockSet::OldBlocks) {
@@ -637,7 +637,7 @@ impl ChainSync {
 	pub fn update_targets(&mut self, chain: &BlockChainClient) {
 		// Do not assume that the block queue/chain still has our last_imported_block
 		let chain = chain.chain_info();
-		self.new_blocks = BlockDownloader::new(false, &chain.best_block_hash, chain.best_block_number);
+		self.new_blocks = BlockDownloader::for_new_blocks(&chain.best_block_hash, chain.best_block_number);
 		self.old_blocks = None;
 		if self.download_old_blocks {
 			if let (Some(ancient_block_hash), Some(ancient_block_number)) = (chain.ancient_block_hash, chain.ancient_block_number) {
@@ -822,11 +822,19 @@ impl ChainSync {
 		if let Some(ref peer) = self.peers.get(&peer_id) {
 			match peer.asking {
 				PeerAsking::BlockHeaders => {
-					if let Some(ref hash) = peer.asking_hash {
-						self.new_blocks.clear_header_download(hash);
-						if let Some(ref mut old) = self.old_blocks {
-							old.clear_header_download(hash);
-						}
+					match peer.asking_hash {
+						Some(ref hash) => {
+							self.new_blocks.clear_header_download(hash);
+							if let Some(ref mut old) = self.old_blocks {
+								old.clear_header_download(hash);
+							}
+						},
+						None => match peer.block_set {
+							Some(BlockSet::OldBlocks) => if let Some(ref mut old) = self.old_blocks {
+								old.clear_header_range_download();
+							},
+							_ => self.new_blocks.clear_header_range_download(),
+						},
 					}
 				},
 				PeerAsking::BlockBodies => {
@@ -855,12 +863,15 @@ impl ChainSync {
 	fn collect_blocks(&mut self, io: &mut SyncIo, block_set: BlockSet) {
 		match block_set {
 			BlockSet::NewBlocks => {
-				if self.new_blocks.collect_blocks(io, self.state == SyncState::NewBlocks) == Err(DownloaderImportError::Invalid) {
+				if let Err(DownloaderImportError::Invalid(_)) = self.new_blocks.collect_blocks(io, self.state == SyncState::NewBlocks) {
 					self.restart(io);
 				}
 			},
 			BlockSet::OldBlocks => {
-				if self.old_blocks.as_mut().map_or(false, |downloader| { downloader.collect_blocks(io, false) == Err(DownloaderImportError::Invalid) }) {
+				if self.old_blocks.as_mut().map_or(false, |downloader| match downloader.collect_blocks(io, false) {
+					Err(DownloaderImportError::Invalid(_)) => true,
+					_ => false,
+				}) {
 					self.restart(io);
 				} else if self.old_blocks.as_ref().map_or(false, |downloader| { downloader.is_complete() }) {
 					trace!(target: "sync", "Background block download is complete");
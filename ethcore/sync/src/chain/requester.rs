@@ -43,7 +43,7 @@ impl SyncRequester {
 	/// Perform block download request`
 	pub fn request_blocks(sync: &mut ChainSync, io: &mut SyncIo, peer_id: PeerId, request: BlockRequest, block_set: BlockSet) {
 		match request {
-			BlockRequest::Headers { start, count, skip } => {
+			BlockRequest::Headers { start, count, skip, .. } => {
 				SyncRequester::request_headers_by_hash(sync, io, peer_id, &start, count, skip, false, block_set);
 			},
 			BlockRequest::Bodies { hashes } => {
@@ -52,6 +52,9 @@ impl SyncRequester {
 			BlockRequest::Receipts { hashes } => {
 				SyncRequester::request_receipts(sync, io, peer_id, hashes, block_set);
 			},
+			BlockRequest::HeadersByNumber { start_number, count } => {
+				SyncRequester::request_headers_by_number(sync, io, peer_id, start_number, count, block_set);
+			},
 		}
 	}
 
@@ -79,6 +82,20 @@ impl SyncRequester {
 		SyncRequester::send_request(sync, io, peer_id, PeerAsking::ForkHeader, GET_BLOCK_HEADERS_PACKET, rlp.out());
 	}
 
+	/// Request consecutive headers from a peer by block number
+	fn request_headers_by_number(sync: &mut ChainSync, io: &mut SyncIo, peer_id: PeerId, n: BlockNumber, count: u64, set: BlockSet) {
+		trace!(target: "sync", "{} <- GetBlockHeaders: {} entries starting from block {}, set = {:?}", peer_id, count, n, set);
+		let mut rlp = RlpStream::new_list(4);
+		rlp.append(&n);
+		rlp.append(&count);
+		rlp.append(&0u32);
+		rlp.append(&0u32);
+		SyncRequester::send_request(sync, io, peer_id, PeerAsking::BlockHeaders, GET_BLOCK_HEADERS_PACKET, rlp.out());
+		let peer = sync.peers.get_mut(&peer_id).expect("peer_id may originate either from on_packet, where it is already validated or from enumerating self.peers. qed");
+		peer.asking_hash = None;
+		peer.block_set = Some(set);
+	}
+
 	/// Find some headers or blocks to download for a peer.
 	pub fn request_snapshot_data(sync: &mut ChainSync, io: &mut SyncIo, peer_id: PeerId) {
 		// find chunk data to download
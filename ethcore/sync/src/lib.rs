@@ -36,6 +36,7 @@ extern crate parking_lot;
 extern crate rlp;
 extern crate keccak_hash as hash;
 extern crate triehash_ethereum;
+extern crate serde;
 
 extern crate ethcore_light as light;
 
@@ -43,6 +44,7 @@ extern crate ethcore_light as light;
 #[cfg(test)] extern crate kvdb_memorydb;
 #[cfg(test)] extern crate rustc_hex;
 #[cfg(test)] extern crate ethcore_private_tx;
+#[cfg(test)] extern crate serde_json;
 
 #[macro_use]
 extern crate macros;
@@ -52,6 +54,8 @@ extern crate log;
 extern crate heapsize;
 #[macro_use]
 extern crate trace_time;
+#[macro_use]
+extern crate serde_derive;
 
 mod chain;
 mod blocks;
@@ -16,16 +16,17 @@
 
 //! Account management (personal) rpc implementation
 use std::sync::Arc;
-use std::collections::btree_map::{BTreeMap, Entry};
+use std::collections::BTreeMap;
 use ethereum_types::Address;
 
 use ethkey::{Brain, Generator, Secret};
 use ethstore::KeyFile;
 use ethcore::account_provider::AccountProvider;
 use jsonrpc_core::Result;
+use jsonrpc_macros::Trailing;
 use v1::helpers::errors;
 use v1::traits::ParityAccounts;
-use v1::types::{H160 as RpcH160, H256 as RpcH256, H520 as RpcH520, Derive, DeriveHierarchical, DeriveHash, ExtAccountInfo};
+use v1::types::{H160 as RpcH160, H256 as RpcH256, H520 as RpcH520, Derive, DeriveHierarchical, DeriveHash, ExtAccountInfo, AccountSource, AccountsInfo, dedup_by_address};
 use ethkey::Password;
 
 /// Account management (personal) rpc implementation.
@@ -43,35 +44,27 @@ impl ParityAccountsClient {
 }
 
 impl ParityAccounts for ParityAccountsClient {
-	fn all_accounts_info(&self) -> Result<BTreeMap<RpcH160, ExtAccountInfo>> {
+	fn all_accounts_info(&self, compact: Trailing<bool>) -> Result<AccountsInfo> {
 		let info = self.accounts.accounts_info().map_err(|e| errors::account("Could not fetch account info.", e))?;
 		let other = self.accounts.addresses_info();
 
 		let account_iter = info
 			.into_iter()
-			.chain(other.into_iter())
-			.map(|(address, v)| (address.into(), ExtAccountInfo {
+			.map(|(address, v)| (address, v, AccountSource::KeyStore))
+			.chain(other.into_iter().map(|(address, v)| (address, v, AccountSource::AddressBook)))
+			.map(|(address, v, source)| (RpcH160::from(address), ExtAccountInfo {
 				name: v.name,
 				meta: v.meta,
-				uuid: v.uuid.map(|uuid| uuid.to_string())
+				uuid: v.uuid.map(|uuid| uuid.to_string()),
+				source,
 			}));
 
-		let mut accounts: BTreeMap<RpcH160, ExtAccountInfo> = BTreeMap::new();
-
-		for (address, account) in account_iter {
-			match accounts.entry(address) {
-				// Insert only if occupied entry isn't already an account with UUID
-				Entry::Occupied(ref mut occupied) if occupied.get().uuid.is_none() => {
-					occupied.insert(account);
-				},
-				Entry::Vacant(vacant) => {
-					vacant.insert(account);
-				},
-				_ => {}
-			};
-		}
-
-		Ok(accounts)
+		let accounts: BTreeMap<_, _> = dedup_by_address(account_iter).into_iter().collect();
+		Ok(if compact.unwrap_or_default() {
+			AccountsInfo::Compact(accounts.iter().map(|(address, info)| (address.clone(), info.into())).collect())
+		} else {
+			AccountsInfo::Full(accounts)
+		})
 	}
 
 	fn new_account_from_phrase(&self, phrase: String, pass: Password) -> Result<RpcH160> {
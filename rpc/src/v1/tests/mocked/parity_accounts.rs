@@ -75,7 +75,24 @@ fn should_be_able_to_get_account_info() {
 
 	let request = r#"{"jsonrpc": "2.0", "method": "parity_allAccountsInfo", "params": [], "id": 1}"#;
 	let res = tester.io.handle_request_sync(request);
-	let response = format!("{{\"jsonrpc\":\"2.0\",\"result\":{{\"0x{:x}\":{{\"meta\":\"{{foo: 69}}\",\"name\":\"Test\",\"uuid\":\"{}\"}}}},\"id\":1}}", address, uuid);
+	let response = format!("{{\"jsonrpc\":\"2.0\",\"result\":{{\"0x{:x}\":{{\"meta\":\"{{foo: 69}}\",\"name\":\"Test\",\"source\":\"keystore\",\"uuid\":\"{}\"}}}},\"id\":1}}", address, uuid);
+	assert_eq!(res, Some(response));
+}
+
+#[test]
+fn should_return_compact_accounts_info() {
+	let tester = setup();
+	tester.accounts.new_account(&"".into()).unwrap();
+	let accounts = tester.accounts.accounts().unwrap();
+	assert_eq!(accounts.len(), 1);
+	let address = accounts[0];
+
+	tester.accounts.set_account_name(address.clone(), "Test".to_owned()).unwrap();
+	tester.accounts.set_account_meta(address.clone(), "{foo: 69}".to_owned()).unwrap();
+
+	let request = r#"{"jsonrpc": "2.0", "method": "parity_allAccountsInfo", "params": [true], "id": 1}"#;
+	let res = tester.io.handle_request_sync(request);
+	let response = format!("{{\"jsonrpc\":\"2.0\",\"result\":{{\"0x{:x}\":{{\"name\":\"Test\",\"source\":\"keystore\"}}}},\"id\":1}}", address);
 	assert_eq!(res, Some(response));
 }
 
@@ -96,7 +113,7 @@ fn should_be_able_to_set_name() {
 
 	let request = r#"{"jsonrpc": "2.0", "method": "parity_allAccountsInfo", "params": [], "id": 1}"#;
 	let res = tester.io.handle_request_sync(request);
-	let response = format!("{{\"jsonrpc\":\"2.0\",\"result\":{{\"0x{:x}\":{{\"meta\":\"{{}}\",\"name\":\"Test\",\"uuid\":\"{}\"}}}},\"id\":1}}", address, uuid);
+	let response = format!("{{\"jsonrpc\":\"2.0\",\"result\":{{\"0x{:x}\":{{\"meta\":\"{{}}\",\"name\":\"Test\",\"source\":\"keystore\",\"uuid\":\"{}\"}}}},\"id\":1}}", address, uuid);
 	assert_eq!(res, Some(response));
 }
 
@@ -117,7 +134,7 @@ fn should_be_able_to_set_meta() {
 
 	let request = r#"{"jsonrpc": "2.0", "method": "parity_allAccountsInfo", "params": [], "id": 1}"#;
 	let res = tester.io.handle_request_sync(request);
-	let response = format!("{{\"jsonrpc\":\"2.0\",\"result\":{{\"0x{:x}\":{{\"meta\":\"{{foo: 69}}\",\"name\":\"\",\"uuid\":\"{}\"}}}},\"id\":1}}", address, uuid);
+	let response = format!("{{\"jsonrpc\":\"2.0\",\"result\":{{\"0x{:x}\":{{\"meta\":\"{{foo: 69}}\",\"name\":\"\",\"source\":\"keystore\",\"uuid\":\"{}\"}}}},\"id\":1}}", address, uuid);
 	assert_eq!(res, Some(response));
 }
 
@@ -156,7 +173,7 @@ fn should_be_able_to_remove_address() {
 	// verify it exists
 	let request = r#"{"jsonrpc": "2.0", "method": "parity_allAccountsInfo", "params": [], "id": 2}"#;
 	let res = tester.io.handle_request_sync(request);
-	let response = r#"{"jsonrpc":"2.0","result":{"0x000baba1000baba2000baba3000baba4000baba5":{"meta":"{}","name":"Test"}},"id":2}"#;
+	let response = r#"{"jsonrpc":"2.0","result":{"0x000baba1000baba2000baba3000baba4000baba5":{"meta":"{}","name":"Test","source":"addressbook"}},"id":2}"#;
 	assert_eq!(res, Some(response.into()));
 
 	// remove the address
@@ -250,7 +267,7 @@ fn rpc_parity_vault_adds_vault_field_to_acount_meta() {
 	assert!(tester.accounts.change_vault(address1, "vault1").is_ok());
 
 	let request = r#"{"jsonrpc": "2.0", "method": "parity_allAccountsInfo", "params":[], "id": 1}"#;
-	let response = format!(r#"{{"jsonrpc":"2.0","result":{{"0x{:x}":{{"meta":"{{\"vault\":\"vault1\"}}","name":"","uuid":"{}"}}}},"id":1}}"#, address1, uuid1);
+	let response = format!(r#"{{"jsonrpc":"2.0","result":{{"0x{:x}":{{"meta":"{{\"vault\":\"vault1\"}}","name":"","source":"keystore","uuid":"{}"}}}},"id":1}}"#, address1, uuid1);
 
 	assert_eq!(tester.io.handle_request_sync(request), Some(response.to_owned()));
 
@@ -258,7 +275,7 @@ fn rpc_parity_vault_adds_vault_field_to_acount_meta() {
 	assert!(tester.accounts.change_vault(address1, "").is_ok());
 
 	let request = r#"{"jsonrpc": "2.0", "method": "parity_allAccountsInfo", "params":[], "id": 1}"#;
-	let response = format!(r#"{{"jsonrpc":"2.0","result":{{"0x{:x}":{{"meta":"{{}}","name":"","uuid":"{}"}}}},"id":1}}"#, address1, uuid1);
+	let response = format!(r#"{{"jsonrpc":"2.0","result":{{"0x{:x}":{{"meta":"{{}}","name":"","source":"keystore","uuid":"{}"}}}},"id":1}}"#, address1, uuid1);
 
 	assert_eq!(tester.io.handle_request_sync(request), Some(response.to_owned()));
 }
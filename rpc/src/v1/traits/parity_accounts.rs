@@ -15,19 +15,18 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 //! Parity Accounts-related rpc interface.
-use std::collections::BTreeMap;
-
 use jsonrpc_core::Result;
+use jsonrpc_macros::Trailing;
 use ethkey::Password;
 use ethstore::KeyFile;
-use v1::types::{H160, H256, H520, DeriveHash, DeriveHierarchical, ExtAccountInfo};
+use v1::types::{H160, H256, H520, AccountsInfo, DeriveHash, DeriveHierarchical};
 
 build_rpc_trait! {
 	/// Personal Parity rpc interface.
 	pub trait ParityAccounts {
-		/// Returns accounts information.
+		/// Returns accounts information. Only names and sources are returned if `compact` is `true`.
 		#[rpc(name = "parity_allAccountsInfo")]
-		fn all_accounts_info(&self) -> Result<BTreeMap<H160, ExtAccountInfo>>;
+		fn all_accounts_info(&self, Trailing<bool>) -> Result<AccountsInfo>;
 
 		/// Creates new account from the given phrase using standard brainwallet mechanism.
 		/// Second parameter is password for the new account.
@@ -14,30 +14,330 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
+use hash::keccak;
+use serde::{Serialize, Serializer};
+use serde_json;
+use v1::types::H160;
+
 /// Account information.
-#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AccountInfo {
 	/// Account name
 	pub name: String,
 }
 
+/// Where an account entry comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum AccountSource {
+	/// Account stored in the local keystore.
+	KeyStore,
+	/// Address book entry (no key available).
+	AddressBook,
+	/// Account held by a hardware wallet.
+	Hardware,
+}
+
+impl Default for AccountSource {
+	fn default() -> Self {
+		AccountSource::KeyStore
+	}
+}
+
 /// Extended account information (used by `parity_allAccountInfo`).
-#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ExtAccountInfo {
 	/// Account name
 	pub name: String,
 	/// Account meta JSON
 	pub meta: String,
-	/// Account UUID (`None` for address book entries)
+	/// Account UUID (only set for keystore accounts)
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub uuid: Option<String>,
+	/// Source of the account
+	#[serde(default)]
+	pub source: AccountSource,
+}
+
+impl ExtAccountInfo {
+	/// Parses the account meta JSON. Empty meta is parsed as an empty object.
+	pub fn parsed_meta(&self) -> Result<serde_json::Value, serde_json::Error> {
+		if self.meta.trim().is_empty() {
+			return Ok(serde_json::Value::Object(Default::default()));
+		}
+		serde_json::from_str(&self.meta)
+	}
+
+	/// Parses the fields of the account meta JSON set by the UI, other fields are ignored.
+	pub fn account_meta(&self) -> Result<AccountMeta, serde_json::Error> {
+		serde_json::from_value(self.parsed_meta()?)
+	}
+}
+
+/// Account meta fields set by the UI.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountMeta {
+	/// Account description
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub description: Option<String>,
+	/// Account tags
+	#[serde(default)]
+	pub tags: Vec<String>,
+}
+
+/// Essentials of an account entry, for clients listing many accounts.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtAccountInfoCompact {
+	/// Account name
+	pub name: String,
+	/// Source of the account
+	pub source: AccountSource,
+}
+
+impl<'a> From<&'a ExtAccountInfo> for ExtAccountInfoCompact {
+	fn from(info: &'a ExtAccountInfo) -> Self {
+		ExtAccountInfoCompact {
+			name: info.name.clone(),
+			source: info.source,
+		}
+	}
+}
+
+/// Response of `parity_allAccountsInfo`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountsInfo {
+	/// Full account information, the default.
+	Full(BTreeMap<H160, ExtAccountInfo>),
+	/// Compact account information, when requested.
+	Compact(BTreeMap<H160, ExtAccountInfoCompact>),
+}
+
+impl Serialize for AccountsInfo {
+	fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error> where S: Serializer {
+		match *self {
+			AccountsInfo::Full(ref accounts) => accounts.serialize(s),
+			AccountsInfo::Compact(ref accounts) => accounts.serialize(s),
+		}
+	}
+}
+
+/// Collapses entries sharing the same address.
+///
+/// An address book entry is replaced by a keystore or hardware entry for the same address;
+/// otherwise the first entry wins. Order of first appearance is preserved.
+pub fn dedup_by_address<I>(accounts: I) -> Vec<(H160, ExtAccountInfo)> where
+	I: IntoIterator<Item = (H160, ExtAccountInfo)>,
+{
+	let mut result: Vec<(H160, ExtAccountInfo)> = Vec::new();
+	let mut positions = HashMap::new();
+
+	for (address, account) in accounts {
+		match positions.entry(address.clone()) {
+			Entry::Occupied(entry) => {
+				let existing = &mut result[*entry.get()].1;
+				if existing.source == AccountSource::AddressBook && account.source != AccountSource::AddressBook {
+					*existing = account;
+				}
+			},
+			Entry::Vacant(entry) => {
+				entry.insert(result.len());
+				result.push((address, account));
+			},
+		}
+	}
+
+	result
+}
+
+/// Checks the EIP-55 mixed-case checksum of a hex address, with or without the `0x` prefix.
+///
+/// Addresses in a single case carry no checksum and are valid. Returns `false` for anything
+/// that is not 20 hex encoded bytes.
+pub fn is_valid_checksum(addr: &str) -> bool {
+	let hex = if addr.starts_with("0x") { &addr[2..] } else { addr };
+	if hex.len() != 40 || !hex.chars().all(|c| c.is_digit(16)) {
+		return false;
+	}
+	if !hex.chars().any(|c| c.is_ascii_lowercase()) || !hex.chars().any(|c| c.is_ascii_uppercase()) {
+		return true;
+	}
+
+	let hash = keccak(hex.to_ascii_lowercase().as_bytes());
+	hex.chars().enumerate().all(|(i, c)| {
+		let nibble = if i % 2 == 0 { hash[i / 2] >> 4 } else { hash[i / 2] & 0x0f };
+		!c.is_ascii_alphabetic() || c.is_ascii_uppercase() == (nibble >= 8)
+	})
 }
 
 /// Hardware wallet information.
-#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct HwAccountInfo {
 	/// Device name.
 	pub name: String,
 	/// Device manufacturer.
 	pub manufacturer: String,
 }
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+	use serde_json;
+	use v1::types::H160;
+	use super::{AccountInfo, AccountMeta, AccountSource, AccountsInfo, ExtAccountInfo, ExtAccountInfoCompact, HwAccountInfo, dedup_by_address, is_valid_checksum};
+
+	#[test]
+	fn ext_account_info_serialization() {
+		let info = ExtAccountInfo {
+			name: "Test".into(),
+			meta: "{}".into(),
+			uuid: Some("uuid".into()),
+			source: AccountSource::KeyStore,
+		};
+		let serialized = serde_json::to_string(&info).unwrap();
+		assert_eq!(serialized, r#"{"name":"Test","meta":"{}","uuid":"uuid","source":"keystore"}"#);
+	}
+
+	#[test]
+	fn account_info_roundtrip() {
+		let info = AccountInfo { name: "Test".into() };
+		let serialized = serde_json::to_string(&info).unwrap();
+		assert_eq!(serde_json::from_str::<AccountInfo>(&serialized).unwrap(), info);
+	}
+
+	#[test]
+	fn ext_account_info_roundtrip() {
+		let with_uuid = ExtAccountInfo {
+			name: "Test".into(),
+			meta: "{}".into(),
+			uuid: Some("uuid".into()),
+			source: AccountSource::KeyStore,
+		};
+		let without_uuid = ExtAccountInfo { uuid: None, source: AccountSource::AddressBook, ..with_uuid.clone() };
+
+		for info in vec![with_uuid, without_uuid] {
+			let serialized = serde_json::to_string(&info).unwrap();
+			assert_eq!(serde_json::from_str::<ExtAccountInfo>(&serialized).unwrap(), info);
+		}
+	}
+
+	#[test]
+	fn hw_account_info_roundtrip() {
+		let info = HwAccountInfo {
+			name: "Nano S".into(),
+			manufacturer: "Ledger".into(),
+		};
+		let serialized = serde_json::to_string(&info).unwrap();
+		assert_eq!(serde_json::from_str::<HwAccountInfo>(&serialized).unwrap(), info);
+	}
+
+	#[test]
+	fn account_source_serialization() {
+		let keystore = r#""keystore""#;
+		let addressbook = r#""addressbook""#;
+		let hardware = r#""hardware""#;
+
+		assert_eq!(serde_json::to_string(&AccountSource::KeyStore).unwrap(), keystore);
+		assert_eq!(serde_json::to_string(&AccountSource::AddressBook).unwrap(), addressbook);
+		assert_eq!(serde_json::to_string(&AccountSource::Hardware).unwrap(), hardware);
+
+		assert_eq!(serde_json::from_str::<AccountSource>(keystore).unwrap(), AccountSource::KeyStore);
+		assert_eq!(serde_json::from_str::<AccountSource>(addressbook).unwrap(), AccountSource::AddressBook);
+		assert_eq!(serde_json::from_str::<AccountSource>(hardware).unwrap(), AccountSource::Hardware);
+	}
+
+	#[test]
+	fn ext_account_info_source_defaults_to_keystore() {
+		let info: ExtAccountInfo = serde_json::from_str(r#"{"name":"Test","meta":"{}","uuid":"uuid"}"#).unwrap();
+		assert_eq!(info.source, AccountSource::KeyStore);
+	}
+
+	#[test]
+	fn dedup_by_address_prefers_keystore() {
+		let account = |name: &str, source| ExtAccountInfo {
+			name: name.into(),
+			source,
+			..Default::default()
+		};
+		let a = H160::from(1);
+		let b = H160::from(2);
+		let c = H160::from(3);
+
+		let deduped = dedup_by_address(vec![
+			(a.clone(), account("a-book", AccountSource::AddressBook)),
+			(b.clone(), account("b-key", AccountSource::KeyStore)),
+			(a.clone(), account("a-key", AccountSource::KeyStore)),
+			(c.clone(), account("c-book", AccountSource::AddressBook)),
+			(b.clone(), account("b-book", AccountSource::AddressBook)),
+		]);
+
+		assert_eq!(deduped, vec![
+			(a, account("a-key", AccountSource::KeyStore)),
+			(b, account("b-key", AccountSource::KeyStore)),
+			(c, account("c-book", AccountSource::AddressBook)),
+		]);
+	}
+
+	#[test]
+	fn compact_accounts_info() {
+		let info = ExtAccountInfo {
+			name: "Test".into(),
+			meta: r#"{"description":"Savings","tags":["cold"]}"#.into(),
+			uuid: Some("d6b4c9d5-8d8a-4b4c-9d35-2d4d6a6a6d3c".into()),
+			source: AccountSource::KeyStore,
+		};
+		let compact = ExtAccountInfoCompact::from(&info);
+		assert_eq!(serde_json::to_string(&compact).unwrap(), r#"{"name":"Test","source":"keystore"}"#);
+
+		let full: BTreeMap<_, _> = (0..1000u64).map(|i| (H160::from(i), info.clone())).collect();
+		let compact: BTreeMap<_, _> = full.iter().map(|(address, info)| (address.clone(), info.into())).collect();
+		let full = serde_json::to_string(&AccountsInfo::Full(full)).unwrap();
+		let compact = serde_json::to_string(&AccountsInfo::Compact(compact)).unwrap();
+		assert!(compact.len() * 2 < full.len(), "compact: {}, full: {}", compact.len(), full.len());
+	}
+
+	#[test]
+	fn parses_account_meta() {
+		let with_meta = |meta: &str| ExtAccountInfo {
+			meta: meta.into(),
+			..Default::default()
+		};
+
+		let info = with_meta(r#"{"description":"Savings","tags":["cold","eth"],"timestamp":1500000000}"#);
+		assert_eq!(info.parsed_meta().unwrap()["timestamp"].as_u64(), Some(1_500_000_000));
+		assert_eq!(info.account_meta().unwrap(), AccountMeta {
+			description: Some("Savings".into()),
+			tags: vec!["cold".into(), "eth".into()],
+		});
+
+		let info = with_meta("");
+		assert_eq!(info.parsed_meta().unwrap(), serde_json::Value::Object(Default::default()));
+		assert_eq!(info.account_meta().unwrap(), AccountMeta::default());
+
+		let info = with_meta("{foo: 69}");
+		assert!(info.parsed_meta().is_err());
+		assert!(info.account_meta().is_err());
+
+		// Well-formed JSON of an unexpected shape.
+		assert!(with_meta(r#"{"tags":"cold"}"#).account_meta().is_err());
+	}
+
+	#[test]
+	fn validates_address_checksum() {
+		for addr in &[
+			"0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+			"0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+			"0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+			"D1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+			"0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed",
+			"0x5AAEB6053F3E94C9B9A09F33669435E7EF1BEAED",
+		] {
+			assert!(is_valid_checksum(addr), "{} should be valid", addr);
+		}
+
+		assert!(!is_valid_checksum("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD"));
+		assert!(!is_valid_checksum("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeA"));
+		assert!(!is_valid_checksum("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAgz"));
+	}
+}